@@ -1,53 +1,210 @@
+#![allow(non_snake_case)]
+// so the derive can use ::BCF paths in here too
+extern crate self as BCF;
 use std::{
     collections::HashMap,
     fmt::Debug,
+    fmt::Display,
     hash::Hash,
-    io::{BufRead, BufReader, Read},
+    io::{BufReader, IsTerminal, Read, Write},
+    net::{IpAddr, SocketAddr},
     ops::{Range},
-    process::exit,
+    path::{Path, PathBuf},
+    time::Duration,
 };
 
+/// where a layer of the config came from, diagnostics point into its text
+#[derive(Debug, Clone)]
+pub struct BCFSource {
+    pub name: String,
+    pub text: String,
+    /// the file it was read from, if it was
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    value: String,
+    /// index into sources
+    source: usize,
+    /// (where in value, where in the source text) for every line the value is on
+    pieces: Vec<(usize, usize)>,
+    /// where the value ends in the source text
+    end: usize,
+}
+
+impl Entry {
+    fn single(value: String, source: usize, offset: usize) -> Self {
+        Entry {
+            end: offset + value.len(),
+            value,
+            source,
+            pieces: vec![(0, offset)],
+        }
+    }
+    /// where at in the value is in the source, the end of a span stays on the line it ends on
+    fn locate(&self, at: usize, end: bool) -> usize {
+        let (pos, offset) = self
+            .pieces
+            .iter()
+            .rev()
+            .find(|(pos, _)| *pos < at || (!end && *pos == at))
+            .unwrap_or(&self.pieces[0]);
+        offset + at - pos
+    }
+}
+
 // Btelnyy config format!!
 #[derive(Debug, Clone)]
 pub struct RawConfig {
-    data: HashMap<String, String>,
-    full: String,
-    offsets: HashMap<String, usize>,
+    data: HashMap<String, Entry>,
+    sources: Vec<BCFSource>,
 }
 
+mod parse;
+use parse::Parser;
+
 use anyhow::{anyhow};
+use humantime::DurationError;
 impl RawConfig {
-    pub fn parse<T: Read>(file: T) -> Result<Self, anyhow::Error> {
+    /// lines that aren't `key:value` are all reported at once
+    pub fn parse<T: Read>(file: T) -> Result<Self, BCFReport> {
+        Self::parse_named("config", file)
+    }
+    /// name is what the file is called in diagnostics, includes are relative to the working directory
+    pub fn parse_named<T: Read>(name: &str, file: T) -> Result<Self, BCFReport> {
+        let mut text = String::new();
+        if let Err(e) = BufReader::new(file).read_to_string(&mut text) {
+            return Err(Self::read_error(name, e));
+        }
+        let mut parser = Parser::default();
+        parser.file(name.to_string(), text, None, "");
+        Self::from_parser(parser)
+    }
+    /// parse_named with the path as the name, a missing file ends up in the report too
+    pub fn open(path: &Path) -> Result<Self, BCFReport> {
+        let name = path.display().to_string();
+        let text = std::fs::read_to_string(path).map_err(|e| Self::read_error(&name, e))?;
+        let mut parser = Parser::default();
+        parser.enter(path);
+        parser.file(name, text, Some(path), "");
+        Self::from_parser(parser)
+    }
+    fn read_error(name: &str, e: std::io::Error) -> BCFReport {
+        BCFReport {
+            sources: vec![],
+            errors: vec![BCFError {
+                kind: BCFErrorKind::Io,
+                key: String::new(),
+                source: 0,
+                span: None,
+                error: anyhow::Error::new(e).context(format!("Failed to read {}", name)),
+            }],
+        }
+    }
+    fn from_parser(parser: Parser) -> Result<Self, BCFReport> {
+        let config = RawConfig {
+            data: parser.data,
+            sources: parser.sources,
+        };
+        if !parser.errors.is_empty() {
+            return Err(config.report(parser.errors));
+        }
+        Ok(config)
+    }
+    /// every `<prefix><KEY>` environment variable, with LURKY_ as the prefix LURKY_AUTH_KEY sets auth_key
+    pub fn from_env(prefix: &str) -> Self {
+        Self::from_vars(prefix, std::env::vars())
+    }
+    /// from_env, but with the variables given
+    pub fn from_vars<I: IntoIterator<Item = (String, String)>>(prefix: &str, vars: I) -> Self {
+        let mut vars: Vec<(String, String)> = vars
+            .into_iter()
+            .filter(|(var, _)| var.len() > prefix.len() && var.starts_with(prefix))
+            .collect();
+        vars.sort();
+        let mut text = String::new();
         let mut data = HashMap::new();
-        let mut raw = String::new();
-        let mut reader = BufReader::new(file);
-        let mut offset = 0;
-        let mut offsets = HashMap::new();
-        loop {
-            let mut line = String::new();
-            let read = match reader.read_line(&mut line) {
-                Ok(0) => break,
-                Ok(e) => e,
-                Err(e) => return Err(e.into()),
-            };
-            raw.push_str(&line);
-            if line.starts_with("#") || line.trim().is_empty() {
-                offset += read;
-                continue;
+        for (var, value) in vars {
+            text.push_str(&var);
+            text.push('=');
+            data.insert(
+                var[prefix.len()..].to_lowercase(),
+                Entry::single(value.clone(), 0, text.len()),
+            );
+            text.push_str(&value);
+            text.push('\n');
+        }
+        RawConfig {
+            data,
+            sources: vec![BCFSource {
+                name: "environment".to_string(),
+                text,
+                path: None,
+            }],
+        }
+    }
+    /// `key=value` overrides, like the ones given on the command line
+    pub fn from_args<I: IntoIterator<Item = String>>(args: I) -> Result<Self, BCFReport> {
+        let mut text = String::new();
+        let mut data = HashMap::new();
+        let mut errors = Vec::new();
+        for arg in args {
+            let start = text.len();
+            text.push_str(&arg);
+            text.push('\n');
+            match arg.split_once('=') {
+                Some((key, value)) => {
+                    data.insert(
+                        key.trim().to_string(),
+                        Entry::single(value.to_string(), 0, start + key.len() + 1),
+                    );
+                }
+                None => errors.push(BCFError {
+                    kind: BCFErrorKind::Syntax,
+                    key: String::new(),
+                    source: 0,
+                    span: Some(start..start + arg.len()),
+                    error: anyhow!("Expected key=value"),
+                }),
             }
-            let mut parts = line.splitn(2, ':');
-            let key = parts.next().ok_or(anyhow!("Unable to find key!"))?.trim();
-            let value = parts.next().ok_or(anyhow!("Unable to find value!"))?.trim();
-            data.insert(key.to_string(), value.to_string());
-            offsets.insert(key.to_string(), offset);
-            offset += read;
         }
-
-        Ok(RawConfig {
+        let config = RawConfig {
             data,
-            full: raw,
-            offsets,
-        })
+            sources: vec![BCFSource {
+                name: "command line".to_string(),
+                text,
+                path: None,
+            }],
+        };
+        if !errors.is_empty() {
+            return Err(config.report(errors));
+        }
+        Ok(config)
+    }
+    /// layers other on top, its values replace ours
+    pub fn merge(mut self, other: RawConfig) -> Self {
+        let shift = self.sources.len();
+        self.sources.extend(other.sources);
+        for (key, entry) in other.data {
+            let entry = Entry {
+                source: entry.source + shift,
+                ..entry
+            };
+            self.data.insert(key, entry);
+        }
+        self
+    }
+    /// every file the config was read from, includes too
+    pub fn files(&self) -> impl Iterator<Item = &Path> {
+        self.sources.iter().filter_map(|s| s.path.as_deref())
+    }
+    /// where the value of key came from
+    pub fn source_of(&self, key: &str) -> Option<&BCFSource> {
+        self.data.get(key).map(|e| &self.sources[e.source])
+    }
+    pub fn has(&self, key: &str) -> bool {
+        self.data.contains_key(key)
     }
     pub fn get<T: BCFValue>(&self, key: &str) -> BCFParseResult<T> {
         self.data
@@ -56,7 +213,123 @@ impl RawConfig {
                 error: anyhow!("Missing key {}", key),
                 span: 0..0,
             })
-            .and_then(|s| T::parse_bcf(s))
+            .and_then(|e| T::parse_bcf(&e.value))
+    }
+    /// like get, but the error is about the whole file instead of the value
+    pub fn value<T: BCFValue>(&self, key: &str) -> Result<T, BCFError> {
+        let Some(entry) = self.data.get(key) else {
+            return Err(BCFError {
+                kind: BCFErrorKind::Missing,
+                key: key.to_string(),
+                source: 0,
+                span: None,
+                error: anyhow!("Missing key {}", key),
+            });
+        };
+        T::parse_bcf(&entry.value).map_err(|e| self.invalid(key, e))
+    }
+    /// an error about the value of key, the span is into the value like it is for parse_bcf.
+    /// for values that parse but still can't be used
+    pub fn invalid(&self, key: &str, error: BCFParseError) -> BCFError {
+        let entry = self.data.get(key);
+        BCFError {
+            kind: BCFErrorKind::Invalid,
+            key: key.to_string(),
+            source: entry.map_or(0, |e| e.source),
+            span: entry.map(|e| e.locate(error.span.start, false)..e.locate(error.span.end, true)),
+            error: error.error,
+        }
+    }
+    /// value, but the error is pushed to errors
+    pub fn collect<T: BCFValue>(&self, key: &str, errors: &mut Vec<BCFError>) -> Option<T> {
+        self.value(key).map_err(|e| errors.push(e)).ok()
+    }
+    pub fn report(&self, errors: Vec<BCFError>) -> BCFReport {
+        BCFReport {
+            sources: self.sources.clone(),
+            errors,
+        }
+    }
+    /// changes the value of key where it is written, everything around it stays the same.
+    /// a value split over lines ends up on one. keys that aren't there yet are added to the end of the first layer.
+    /// keys from the environment or the command line can't be set, nothing would ever write them
+    pub fn set<T: BCFValue>(&mut self, key: &str, value: &T) -> Result<(), anyhow::Error> {
+        let value = value.to_bcf();
+        if value.contains('\n') || key.contains(['\n', ':']) || key.trim() != key {
+            return Err(anyhow!("{}:{} can't be written on one line", key, value));
+        }
+        // it would carry on onto the next line when read back, and there is no way to escape it
+        if parse::continues(value.trim_end()) {
+            return Err(anyhow!(
+                "{}:{} ends in a \\, it would swallow the line after it",
+                key,
+                value
+            ));
+        }
+        if let Some(entry) = self.data.get(key) {
+            let source = &self.sources[entry.source];
+            if entry.source != 0 && source.path.is_none() {
+                return Err(anyhow!(
+                    "{} comes from the {}, change it there",
+                    key,
+                    source.name
+                ));
+            }
+        }
+        let Some(entry) = self.data.get(key) else {
+            let text = &mut self.sources[0].text;
+            if !text.is_empty() && !text.ends_with('\n') {
+                text.push('\n');
+            }
+            if parse::ends_continued(text) {
+                text.push('\n');
+            }
+            // dotted keys work outside of sections too
+            if !parse::last_section(text).is_empty() {
+                text.push_str("[]\n");
+            }
+            text.push_str(key);
+            text.push(':');
+            let offset = text.len();
+            text.push_str(&value);
+            text.push('\n');
+            self.data
+                .insert(key.to_string(), Entry::single(value, 0, offset));
+            return Ok(());
+        };
+        let (source, start, end) = (entry.source, entry.pieces[0].1, entry.end);
+        self.sources[source].text.replace_range(start..end, &value);
+        // everything after it in the same layer moved
+        for other in self.data.values_mut() {
+            if other.source == source && other.end > end {
+                for piece in other.pieces.iter_mut() {
+                    piece.1 = piece.1 + value.len() - (end - start);
+                }
+                other.end = other.end + value.len() - (end - start);
+            }
+        }
+        let entry = self.data.get_mut(key).expect("key was just there");
+        *entry = Entry::single(value, source, start);
+        Ok(())
+    }
+    /// writes out the first layer, the file when it was read with open or parse.
+    /// keys from an @include are in their own file, use write_files to keep those
+    pub fn write<W: Write>(&self, mut out: W) -> std::io::Result<()> {
+        out.write_all(self.sources[0].text.as_bytes())
+    }
+    /// writes every layer that was read from a file back to it, includes too.
+    /// files that didn't change are left alone, so their modification time stays
+    pub fn write_files(&self) -> std::io::Result<()> {
+        for source in &self.sources {
+            let Some(path) = &source.path else {
+                continue;
+            };
+            if std::fs::read_to_string(path).is_ok_and(|text| text == source.text) {
+                continue;
+            }
+            std::fs::write(path, &source.text)?;
+        }
+        Ok(())
     }
 }
 #[derive(Debug)]
@@ -72,23 +345,112 @@ where
     Self: Sized,
 {
     fn parse_bcf(value: &str) -> BCFParseResult<Self>;
+    /// what parse_bcf would read back as the same value
+    fn to_bcf(&self) -> String;
 }
 
-impl BCFValue for u64 {
+/// for anything with a FromStr, the whole value is the span
+macro_rules! from_str_value {
+    ($($ty:ty),*) => {
+        $(impl BCFValue for $ty {
+            fn parse_bcf(value: &str) -> BCFParseResult<Self> {
+                value.parse().map_err(|err| BCFParseError {
+                    span: 0..value.len(),
+                    error: anyhow::Error::new(err),
+                })
+            }
+            fn to_bcf(&self) -> String {
+                self.to_string()
+            }
+        })*
+    };
+}
+from_str_value!(
+    u16,
+    u32,
+    u64,
+    usize,
+    i32,
+    i64,
+    f64,
+    IpAddr,
+    SocketAddr,
+    url::Url
+);
+
+impl BCFValue for bool {
     fn parse_bcf(value: &str) -> BCFParseResult<Self> {
-        value.parse().map_err(|err| BCFParseError {
-            span: 0..value.len(),
-            error: anyhow::Error::new(err),
-        })
+        match value {
+            "true" | "yes" | "on" => Ok(true),
+            "false" | "no" | "off" => Ok(false),
+            _ => Err(BCFParseError {
+                span: 0..value.len(),
+                error: anyhow!("Expected true or false"),
+            }),
+        }
+    }
+    fn to_bcf(&self) -> String {
+        self.to_string()
     }
 }
-impl BCFValue for u16 {
+
+/// empty is None
+impl<T: BCFValue> BCFValue for Option<T> {
     fn parse_bcf(value: &str) -> BCFParseResult<Self> {
-        value.parse().map_err(|err| BCFParseError {
-            span: 0..value.len(),
-            error: anyhow::Error::new(err),
+        match value.is_empty() {
+            true => Ok(None),
+            false => T::parse_bcf(value).map(Some),
+        }
+    }
+    fn to_bcf(&self) -> String {
+        self.as_ref().map(T::to_bcf).unwrap_or_default()
+    }
+}
+
+/// `30s`, `5m`, `1h 30m` and so on, a plain number is seconds
+impl BCFValue for Duration {
+    fn parse_bcf(value: &str) -> BCFParseResult<Self> {
+        if let Ok(secs) = value.parse() {
+            return Ok(Duration::from_secs(secs));
+        }
+        humantime::parse_duration(value).map_err(|err| {
+            let span = match err {
+                DurationError::InvalidCharacter(at) | DurationError::NumberExpected(at) => {
+                    at..at + value[at..].chars().next().map_or(0, char::len_utf8)
+                }
+                // a number without a unit, point at the number
+                DurationError::UnknownUnit { start, end, .. } if start == end => {
+                    value[..start]
+                        .trim_end_matches(|c: char| c.is_ascii_digit())
+                        .len()..start
+                }
+                DurationError::UnknownUnit { start, end, .. } => start..end,
+                _ => 0..value.len(),
+            };
+            BCFParseError {
+                span,
+                error: anyhow::Error::new(err),
+            }
         })
     }
+    fn to_bcf(&self) -> String {
+        humantime::format_duration(*self).to_string()
+    }
+}
+
+impl BCFValue for PathBuf {
+    fn parse_bcf(value: &str) -> BCFParseResult<Self> {
+        if value.is_empty() {
+            return Err(BCFParseError {
+                span: 0..0,
+                error: anyhow!("Expected a path"),
+            });
+        }
+        Ok(PathBuf::from(value))
+    }
+    fn to_bcf(&self) -> String {
+        self.to_string_lossy().into_owned()
+    }
 }
 // thank you rossetta code
 const ESCAPE: char = '\\';
@@ -116,6 +478,17 @@ fn tokenize(string: &str, sep: char) -> Vec<(String, usize)> {
     tokens.push((token, escapes_hit));
     tokens
 }
+/// the other way around, so tokenize gives string back
+fn escape(string: &str, sep: char) -> String {
+    let mut escaped = String::with_capacity(string.len());
+    for ch in string.chars() {
+        if ch == sep || ch == ESCAPE {
+            escaped.push(ESCAPE);
+        }
+        escaped.push(ch);
+    }
+    escaped
+}
 
 impl<T: BCFValue> BCFValue for Vec<T> {
     fn parse_bcf(value: &str) -> BCFParseResult<Self> {
@@ -141,6 +514,12 @@ impl<T: BCFValue> BCFValue for Vec<T> {
         }
         Ok(resulting)
     }
+    fn to_bcf(&self) -> String {
+        self.iter()
+            .map(|v| escape(&v.to_bcf(), ','))
+            .collect::<Vec<String>>()
+            .join(",")
+    }
 }
 // there is some fuckery with errors with this one but i dont think im ever going to use it/touch it again so it stays
 impl<K: Hash + Eq + BCFValue, V: BCFValue> BCFValue for HashMap<K, V> {
@@ -188,54 +567,159 @@ impl<K: Hash + Eq + BCFValue, V: BCFValue> BCFValue for HashMap<K, V> {
         }
         Ok(resulting)
     }
+    /// sorted, so writing the same map twice gives the same config
+    fn to_bcf(&self) -> String {
+        let mut pairs: Vec<String> = self
+            .iter()
+            .map(|(k, v)| format!("{}|{}", escape(&k.to_bcf(), '|'), escape(&v.to_bcf(), '|')))
+            .collect();
+        pairs.sort();
+        pairs.to_bcf()
+    }
 }
 
 impl BCFValue for String {
     fn parse_bcf(value: &str) -> BCFParseResult<Self> {
         Ok(value.to_string())
     }
+    fn to_bcf(&self) -> String {
+        self.clone()
+    }
 }
 
 use codespan_reporting::files::SimpleFiles;
-use codespan_reporting::term::termcolor::{ColorChoice, StandardStream};
+use codespan_reporting::term::termcolor::{ColorChoice, StandardStream, WriteColor};
 use codespan_reporting::{
     diagnostic::{Diagnostic, Label},
     term,
 };
 
-pub fn bcf_parse_into<T: BCFValue>(conf: &RawConfig, key: &str) -> T {
-    match conf.get::<T>(key) {
-        Ok(a) => a,
-        Err(e) => {
-            // oh shit
-            // we got an error
-            let offset = conf.offsets.get(key).unwrap_or(&0);
-            let mut files = SimpleFiles::new();
-            let conf_file = files.add("config", conf.full.clone());
-            let err_message = format!("{:?}", e.error);
-            let err_span =
-                e.span.start + offset + key.len() + 1..e.span.end + offset + key.len() + 1;
-            let diag = Diagnostic::error()
-                .with_message(err_message.clone())
-                .with_code("ERROR")
-                .with_labels(vec![
-                    Label::primary(conf_file, err_span).with_message(err_message)
-                ]);
-            let writer = StandardStream::stderr(ColorChoice::Always);
-            let config = codespan_reporting::term::Config::default();
-
-            term::emit(&mut writer.lock(), &config, &files, &diag).unwrap();
-            exit(1)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BCFErrorKind {
+    /// a line that isn't key:value
+    Syntax,
+    Missing,
+    Invalid,
+    /// the config couldn't be read at all
+    Io,
+}
+
+/// one problem with a config, unlike BCFParseError the span is into the whole file
+#[derive(Debug)]
+pub struct BCFError {
+    pub kind: BCFErrorKind,
+    /// empty for syntax and io errors
+    pub key: String,
+    /// which of the report's sources span is in
+    pub source: usize,
+    /// None if there is nothing in the file to point at, like for missing keys
+    pub span: Option<Range<usize>>,
+    pub error: anyhow::Error,
+}
+
+impl BCFError {
+    /// file is the id of the error's source in the files it is emitted with
+    pub fn diagnostic(&self, file: usize) -> Diagnostic<usize> {
+        let err_message = format!("{:?}", self.error);
+        let diag = Diagnostic::error()
+            .with_message(err_message.clone())
+            .with_code("ERROR");
+        match (&self.span, self.kind) {
+            (Some(span), _) => diag.with_labels(vec![
+                Label::primary(file, span.clone()).with_message(err_message)
+            ]),
+            (None, BCFErrorKind::Missing) => {
+                diag.with_notes(vec![format!("add a `{}:<value>` line", self.key)])
+            }
+            (None, _) => diag,
         }
     }
 }
 
+/// every problem found while reading a config
+#[derive(Debug)]
+pub struct BCFReport {
+    /// the layers of the config the errors point into
+    pub sources: Vec<BCFSource>,
+    pub errors: Vec<BCFError>,
+}
+
+impl BCFReport {
+    pub fn emit(
+        &self,
+        writer: &mut dyn WriteColor,
+    ) -> Result<(), codespan_reporting::files::Error> {
+        let mut files = SimpleFiles::new();
+        let ids: Vec<usize> = self
+            .sources
+            .iter()
+            .map(|s| files.add(s.name.as_str(), s.text.as_str()))
+            .collect();
+        let config = codespan_reporting::term::Config::default();
+        for error in &self.errors {
+            let file = ids.get(error.source).copied().unwrap_or_default();
+            term::emit(writer, &config, &files, &error.diagnostic(file))?;
+        }
+        Ok(())
+    }
+    /// emit to stderr, in color if it is a terminal (and NO_COLOR or TERM=dumb don't say otherwise)
+    pub fn eprint(&self) {
+        // Auto only looks at the environment, so a redirected stderr would still get escape codes
+        let color = match std::io::stderr().is_terminal() {
+            true => ColorChoice::Auto,
+            false => ColorChoice::Never,
+        };
+        let writer = StandardStream::stderr(color);
+        self.emit(&mut writer.lock())
+            .expect("Failed to write config errors");
+    }
+}
+
+impl Display for BCFReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} problem(s) with the config", self.errors.len())?;
+        for error in &self.errors {
+            match error.key.is_empty() {
+                true => write!(f, "\n{}", error.error)?,
+                false => write!(f, "\n{}: {}", error.key, error.error)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for BCFReport {}
+
+/// a struct that can be read from a config, use #[derive(BCFConfig)].
+///
+/// field attributes:
+/// * `#[bcf(rename = "key")]` reads the field from another key
+/// * `#[bcf(default)]` or `#[bcf(default = expr)]` for keys that can be left out, string literals are turned into the field with Into
+/// * `#[bcf(prefix = "abc_")]` or `#[bcf(nested)]` (prefix is the field name and `_`) read a BCFConfig field from keys starting with the prefix
+///
+/// `Option<T>` fields are None when the key isn't there.
+/// `#[bcf(default)]` on the struct takes every missing field from its Default impl
+pub trait BCFConfig: Sized {
+    /// reads every field from keys starting with prefix, problems are pushed to errors.
+    /// None if any field couldn't be read
+    fn collect_bcf(conf: &RawConfig, prefix: &str, errors: &mut Vec<BCFError>) -> Option<Self>;
+    fn from_bcf(conf: &RawConfig) -> Result<Self, BCFReport> {
+        let mut errors = Vec::new();
+        match Self::collect_bcf(conf, "", &mut errors) {
+            Some(value) if errors.is_empty() => Ok(value),
+            _ => Err(conf.report(errors)),
+        }
+    }
+}
+
+pub use BCF_derive::BCFConfig;
+
 #[cfg(test)]
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
     use super::*;
     use indoc::indoc;
-    #[derive(Debug)]
+    #[derive(Debug, BCFConfig)]
     struct TestConfig {
         val1: u64,
         val2: u64,
@@ -245,17 +729,27 @@ mod tests {
         test_map_two: HashMap<u64, u64>,
     }
 
-    impl TestConfig {
-        fn parse_from_bcf(conf: RawConfig) -> Self {
-            Self {
-                val1: bcf_parse_into(&conf, "val1"),
-                val2: bcf_parse_into(&conf, "val2"),
-                banned_mfs: bcf_parse_into(&conf, "banned_mfs"),
-                escape_test: bcf_parse_into(&conf, "escape_test"),
-                test_map: bcf_parse_into(&conf, "test_map"),
-                test_map_two: bcf_parse_into(&conf, "test_map_two"),
-            }
-        }
+    #[derive(Debug, Default, BCFConfig)]
+    #[bcf(default)]
+    struct Limits {
+        low: u16,
+        high: u16,
+    }
+
+    #[derive(Debug, BCFConfig)]
+    struct AttrConfig {
+        #[bcf(rename = "port")]
+        listen_port: u16,
+        #[bcf(default = "localhost")]
+        host: String,
+        #[bcf(default)]
+        tags: Vec<String>,
+        motd: Option<String>,
+        banner: Option<String>,
+        #[bcf(nested)]
+        limits: Limits,
+        #[bcf(prefix = "other_")]
+        other_limits: Limits,
     }
 
     #[test]
@@ -270,7 +764,7 @@ mod tests {
         "};
 
         let conf = RawConfig::parse(data.as_bytes()).expect("Failed to parse config");
-        let dt = TestConfig::parse_from_bcf(conf);
+        let dt = TestConfig::from_bcf(&conf).expect("Failed to read config");
         assert_eq!(dt.val1, 65535);
         assert_eq!(dt.val2, 1234);
         assert_eq!(dt.banned_mfs, vec![6969, 420, 5923]);
@@ -292,4 +786,294 @@ mod tests {
         );
         println!("{:#?}", dt);
     }
+
+    #[test]
+    pub fn derive_attributes() {
+        let data = indoc! {"
+        port:8080
+        motd:hello
+        limits_high:10
+        other_low:1
+        other_high:2
+        "};
+
+        let conf = RawConfig::parse(data.as_bytes()).expect("Failed to parse config");
+        let dt = AttrConfig::from_bcf(&conf).expect("Failed to read config");
+        assert_eq!(dt.listen_port, 8080);
+        assert_eq!(dt.host, "localhost");
+        assert!(dt.tags.is_empty());
+        assert_eq!(dt.motd.as_deref(), Some("hello"));
+        assert_eq!(dt.banner, None);
+        assert_eq!((dt.limits.low, dt.limits.high), (0, 10));
+        assert_eq!((dt.other_limits.low, dt.other_limits.high), (1, 2));
+    }
+
+    #[test]
+    pub fn report_collects_everything() {
+        let data = indoc! {"
+        val1:nope
+        banned_mfs:1,x,3
+        escape_test:a
+        test_map:a|b
+        test_map_two:1|2
+        "};
+
+        let conf = RawConfig::parse(data.as_bytes()).expect("Failed to parse config");
+        let report = TestConfig::from_bcf(&conf).expect_err("Config should be invalid");
+        let errors: Vec<_> = report
+            .errors
+            .iter()
+            .map(|e| (e.key.as_str(), e.kind, e.span.clone()))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                ("val1", BCFErrorKind::Invalid, Some(5..9)),
+                ("val2", BCFErrorKind::Missing, None),
+                ("banned_mfs", BCFErrorKind::Invalid, Some(23..24)),
+            ]
+        );
+        assert_eq!(&data[5..9], "nope");
+        assert_eq!(&data[23..24], "x");
+
+        let report = RawConfig::parse("a:b\noops\nc:d\nagain".as_bytes())
+            .expect_err("Config should not parse");
+        let spans: Vec<_> = report.errors.iter().map(|e| e.span.clone()).collect();
+        assert_eq!(spans, vec![Some(4..8), Some(13..18)]);
+    }
+
+    #[test]
+    pub fn layers_point_at_their_source() {
+        let file = RawConfig::parse_named("test.conf", "val1 : 1\nval2:2\n".as_bytes())
+            .expect("Failed to parse config");
+        let env = RawConfig::from_vars(
+            "TEST_",
+            vec![
+                ("TEST_VAL2".to_string(), "x2".to_string()),
+                ("OTHER_VAL1".to_string(), "3".to_string()),
+            ],
+        );
+        let args =
+            RawConfig::from_args(vec!["banned_mfs=1,y".to_string()]).expect("Failed to parse args");
+        let conf = file.merge(env).merge(args);
+        assert_eq!(conf.get::<u64>("val1").unwrap(), 1);
+        assert_eq!(conf.source_of("val1").unwrap().name, "test.conf");
+        assert_eq!(conf.source_of("val2").unwrap().name, "environment");
+        assert_eq!(conf.source_of("banned_mfs").unwrap().name, "command line");
+
+        let report = TestConfig::from_bcf(&conf).expect_err("Config should be invalid");
+        let errors: Vec<_> = report
+            .errors
+            .iter()
+            .filter(|e| e.kind == BCFErrorKind::Invalid)
+            .map(|e| {
+                let source = &report.sources[e.source];
+                (source.name.as_str(), &source.text[e.span.clone().unwrap()])
+            })
+            .collect();
+        assert_eq!(errors, vec![("environment", "x2"), ("command line", "y")]);
+
+        let report = RawConfig::from_args(vec!["a=b".to_string(), "oops".to_string()])
+            .expect_err("Args should not parse");
+        assert_eq!(report.errors[0].span, Some(4..8));
+
+        // only the file gets written, so the other layers can't be set
+        let mut conf = conf;
+        assert!(conf.set("val2", &5u64).is_err());
+        assert!(conf.set("banned_mfs", &vec![1u64]).is_err());
+        conf.set("val1", &5u64).unwrap();
+        let mut out = Vec::new();
+        conf.write(&mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "val1 : 5\nval2:2\n");
+    }
+
+    #[test]
+    pub fn more_values() {
+        assert!(bool::parse_bcf("yes").unwrap());
+        assert!(!bool::parse_bcf("off").unwrap());
+        assert_eq!(bool::parse_bcf("maybe").unwrap_err().span, 0..5);
+        assert_eq!(i32::parse_bcf("-12").unwrap(), -12);
+        assert_eq!(u32::parse_bcf("-12").unwrap_err().span, 0..3);
+        assert_eq!(f64::parse_bcf("0.5").unwrap(), 0.5);
+        assert_eq!(<Option<u64>>::parse_bcf("").unwrap(), None);
+        assert_eq!(<Option<u64>>::parse_bcf("4").unwrap(), Some(4));
+        assert_eq!(Duration::parse_bcf("60").unwrap(), Duration::from_secs(60));
+        assert_eq!(
+            Duration::parse_bcf("1h 30m").unwrap(),
+            Duration::from_secs(90 * 60)
+        );
+        assert_eq!(Duration::parse_bcf("5m 3x").unwrap_err().span, 4..5);
+        assert_eq!(Duration::parse_bcf("5m 3").unwrap_err().span, 3..4);
+        assert_eq!(
+            SocketAddr::parse_bcf("127.0.0.1:80").unwrap(),
+            SocketAddr::from(([127, 0, 0, 1], 80))
+        );
+        assert_eq!(SocketAddr::parse_bcf("localhost").unwrap_err().span, 0..9);
+        assert!(PathBuf::parse_bcf("").is_err());
+        assert_eq!(
+            url::Url::parse_bcf("https://example.com/a").unwrap().path(),
+            "/a"
+        );
+        assert!(url::Url::parse_bcf("nope").is_err());
+        // spans inside lists still line up
+        assert_eq!(<Vec<Duration>>::parse_bcf("5s,1q").unwrap_err().span, 4..5);
+    }
+
+    #[test]
+    pub fn round_trip() {
+        let data = indoc! {"
+        # the servers
+        val1:1
+        escape_test:hello\\, world,hey!
+        # keep me
+        test_map:a|b
+        val2 : 2
+        "};
+        let mut conf = RawConfig::parse(data.as_bytes()).expect("Failed to parse config");
+        conf.set("val1", &1234u64).unwrap();
+        conf.set("escape_test", &vec!["a,b".to_string(), "c\\d".to_string()])
+            .unwrap();
+        conf.set(
+            "test_map",
+            &HashMap::from_iter(vec![("x|y".to_string(), "z,w".to_string())]),
+        )
+        .unwrap();
+        conf.set("val2", &Duration::from_secs(90)).unwrap();
+        conf.set("banned_mfs", &vec![1u64, 2]).unwrap();
+        assert!(conf.set("val1", &"two\nlines".to_string()).is_err());
+        assert!(conf.set("val1", &"C:\\cats\\".to_string()).is_err());
+        assert!(conf.set("val1", &"C:\\cats\\ ".to_string()).is_err());
+
+        let mut out = Vec::new();
+        conf.write(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(
+            out,
+            indoc! {"
+            # the servers
+            val1:1234
+            escape_test:a\\,b,c\\\\d
+            # keep me
+            test_map:x\\\\|y|z\\,w
+            val2 : 1m 30s
+            banned_mfs:1,2
+            "}
+        );
+
+        // and it reads back the same
+        let conf = RawConfig::parse(out.as_bytes()).expect("Failed to parse config");
+        assert_eq!(conf.get::<u64>("val1").unwrap(), 1234);
+        assert_eq!(
+            conf.get::<Vec<String>>("escape_test").unwrap(),
+            vec!["a,b".to_string(), "c\\d".to_string()]
+        );
+        assert_eq!(
+            conf.get::<HashMap<String, String>>("test_map").unwrap(),
+            HashMap::from_iter(vec![("x|y".to_string(), "z,w".to_string())])
+        );
+        assert_eq!(
+            conf.get::<Duration>("val2").unwrap(),
+            Duration::from_secs(90)
+        );
+        assert_eq!(conf.get::<Vec<u64>>("banned_mfs").unwrap(), vec![1, 2]);
+
+        // an even number of them is fine, and doesn't take the next key with it
+        let mut conf = RawConfig::parse("a:1\nb:2\n".as_bytes()).unwrap();
+        conf.set("a", &"C:\\cats\\\\".to_string()).unwrap();
+        let mut out = Vec::new();
+        conf.write(&mut out).unwrap();
+        let conf = RawConfig::parse(out.as_slice()).unwrap();
+        assert_eq!(conf.get::<String>("a").unwrap(), "C:\\cats\\\\");
+        assert_eq!(conf.get::<u64>("b").unwrap(), 2);
+    }
+
+    #[test]
+    pub fn sections_includes_and_continuations() {
+        let dir = std::env::temp_dir().join(format!("bcf-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let main = indoc! {"
+        # top
+        val1:1
+        @include secrets.conf
+        [maps]
+        test_map:a|b,\\
+          c|d
+        []
+        banned_mfs:1,\\
+           x,3
+        "};
+        std::fs::write(dir.join("main.conf"), main).unwrap();
+        std::fs::write(dir.join("secrets.conf"), "val2:2\n@include main.conf\n").unwrap();
+
+        let report = RawConfig::open(&dir.join("main.conf")).expect_err("Includes should cycle");
+        assert_eq!(report.errors.len(), 1);
+        let error = &report.errors[0];
+        let source = &report.sources[error.source];
+        assert!(source.name.ends_with("secrets.conf"));
+        assert_eq!(
+            &source.text[error.span.clone().unwrap()],
+            "@include main.conf"
+        );
+        assert!(error.error.to_string().starts_with("@include cycle"));
+
+        std::fs::write(dir.join("secrets.conf"), "val2:2\n").unwrap();
+        let mut conf = RawConfig::open(&dir.join("main.conf")).expect("Failed to parse config");
+        assert!(conf
+            .source_of("val2")
+            .unwrap()
+            .name
+            .ends_with("secrets.conf"));
+        assert_eq!(conf.files().count(), 2);
+        assert_eq!(
+            conf.get::<HashMap<String, String>>("maps.test_map")
+                .unwrap(),
+            HashMap::from_iter(vec![
+                ("a".to_string(), "b".to_string()),
+                ("c".to_string(), "d".to_string())
+            ])
+        );
+        let error = conf.value::<Vec<u64>>("banned_mfs").unwrap_err();
+        assert_eq!(&main[error.span.unwrap()], "x");
+
+        conf.set("banned_mfs", &vec![1u64, 2]).unwrap();
+        conf.set("maps.test_map", &HashMap::from_iter(vec![(1u64, 2u64)]))
+            .unwrap();
+        conf.set("val1", &5u64).unwrap();
+        let mut out = Vec::new();
+        conf.write(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            indoc! {"
+            # top
+            val1:5
+            @include secrets.conf
+            [maps]
+            test_map:1|2
+            []
+            banned_mfs:1,2
+            "}
+        );
+
+        // keys from an include go back into it
+        conf.set("val2", &3u64).unwrap();
+        conf.write_files().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.join("secrets.conf")).unwrap(),
+            "val2:3\n"
+        );
+        let conf = RawConfig::open(&dir.join("main.conf")).expect("Failed to parse config");
+        assert_eq!(conf.get::<u64>("val2").unwrap(), 3);
+        assert_eq!(conf.get::<u64>("val1").unwrap(), 5);
+
+        let mut conf = RawConfig::parse("[a]\nb:1\n".as_bytes()).unwrap();
+        assert_eq!(conf.get::<u64>("a.b").unwrap(), 1);
+        conf.set("c.d", &2u64).unwrap();
+        let mut out = Vec::new();
+        conf.write(&mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "[a]\nb:1\n[]\nc.d:2\n");
+
+        let report = RawConfig::parse("[a b]\n@includex\n".as_bytes()).unwrap_err();
+        assert_eq!(report.errors.len(), 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
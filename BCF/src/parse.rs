@@ -0,0 +1,191 @@
+// the text side of BCF, turns files into entries
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::anyhow;
+
+use crate::{BCFError, BCFErrorKind, BCFSource, Entry, ESCAPE};
+
+/// a line ending in an unescaped \ carries on onto the next one
+pub(crate) fn continues(line: &str) -> bool {
+    line.chars().rev().take_while(|c| *c == ESCAPE).count() % 2 == 1
+}
+
+/// the name inside a `[section]` header
+fn section_header(line: &str) -> Option<&str> {
+    line.strip_prefix('[')?.strip_suffix(']').map(str::trim)
+}
+
+/// the section the end of text is in, so keys added to it know if they'd end up in one
+pub(crate) fn last_section(text: &str) -> &str {
+    text.lines()
+        .rev()
+        .find_map(|line| section_header(line.trim()))
+        .unwrap_or("")
+}
+
+/// whether the last line of text would swallow the next one
+pub(crate) fn ends_continued(text: &str) -> bool {
+    text.lines().last().is_some_and(|l| continues(l.trim_end()))
+}
+
+#[derive(Default)]
+pub(crate) struct Parser {
+    pub data: HashMap<String, Entry>,
+    pub sources: Vec<BCFSource>,
+    pub errors: Vec<BCFError>,
+    /// the files being parsed right now, an include of one of them would never end
+    stack: Vec<PathBuf>,
+}
+
+impl Parser {
+    /// parses text as a new source, includes in it are relative to path.
+    /// keys start out in section, like the ones in an included file do
+    pub fn file(&mut self, name: String, text: String, path: Option<&Path>, section: &str) {
+        let source = self.sources.len();
+        self.sources.push(BCFSource {
+            name,
+            text: text.clone(),
+            path: path.map(Path::to_path_buf),
+        });
+        // (start, line without the newline)
+        let mut lines = Vec::new();
+        let mut offset = 0;
+        for line in text.split_inclusive('\n') {
+            lines.push((offset, line.trim_end_matches('\n').trim_end_matches('\r')));
+            offset += line.len();
+        }
+        let dir = path.and_then(Path::parent);
+        let mut section = section.to_string();
+        let mut lines = lines.into_iter();
+        while let Some((start, line)) = lines.next() {
+            if line.starts_with('#') || line.trim().is_empty() {
+                continue;
+            }
+            let span = start + line.len() - line.trim_start().len()..start + line.trim_end().len();
+            if let Some(name) = section_header(line.trim()) {
+                if name.contains(|c: char| c.is_whitespace() || c == ':') {
+                    self.error(
+                        source,
+                        span,
+                        anyhow!("Section names can't have spaces or :"),
+                    );
+                    continue;
+                }
+                section = name.to_string();
+                continue;
+            }
+            if let Some(include) = line.trim().strip_prefix("@include") {
+                match include.starts_with(char::is_whitespace) {
+                    true => self.include(source, span, include.trim(), dir, &section),
+                    false => self.error(source, span, anyhow!("Expected @include <path>")),
+                }
+                continue;
+            }
+            let Some((key, value)) = line.split_once(':') else {
+                self.error(source, span, anyhow!("Expected key:value"));
+                continue;
+            };
+            let mut value_text = String::new();
+            let mut pieces = Vec::new();
+            let mut piece = value.trim_start();
+            let mut piece_start = start + line.len() - piece.len();
+            let end = loop {
+                let trimmed = piece.trim_end();
+                if continues(trimmed) {
+                    if let Some((next_start, next)) = lines.next() {
+                        pieces.push((value_text.len(), piece_start));
+                        value_text.push_str(&trimmed[..trimmed.len() - 1]);
+                        piece = next.trim_start();
+                        piece_start = next_start + next.len() - piece.len();
+                        continue;
+                    }
+                }
+                pieces.push((value_text.len(), piece_start));
+                value_text.push_str(trimmed);
+                break piece_start + trimmed.len();
+            };
+            let key = match section.is_empty() {
+                true => key.trim().to_string(),
+                false => format!("{}.{}", section, key.trim()),
+            };
+            self.data.insert(
+                key,
+                Entry {
+                    value: value_text,
+                    source,
+                    pieces,
+                    end,
+                },
+            );
+        }
+    }
+
+    /// reads the file at path, relative to the file the include is in
+    fn include(
+        &mut self,
+        source: usize,
+        span: std::ops::Range<usize>,
+        path: &str,
+        dir: Option<&Path>,
+        section: &str,
+    ) {
+        let path = match dir {
+            Some(dir) => dir.join(path),
+            None => PathBuf::from(path),
+        };
+        let read = fs::canonicalize(&path).and_then(|full| Ok((fs::read_to_string(&full)?, full)));
+        let (text, full) = match read {
+            Ok(read) => read,
+            Err(e) => {
+                let error =
+                    anyhow::Error::new(e).context(format!("Failed to read {}", path.display()));
+                self.errors.push(BCFError {
+                    kind: BCFErrorKind::Io,
+                    key: String::new(),
+                    source,
+                    span: Some(span),
+                    error,
+                });
+                return;
+            }
+        };
+        if self.stack.contains(&full) {
+            let chain: Vec<String> = self
+                .stack
+                .iter()
+                .chain(Some(&full))
+                .map(|p| p.display().to_string())
+                .collect();
+            self.error(
+                source,
+                span,
+                anyhow!("@include cycle: {}", chain.join(" -> ")),
+            );
+            return;
+        }
+        self.stack.push(full.clone());
+        self.file(path.display().to_string(), text, Some(&full), section);
+        self.stack.pop();
+    }
+
+    /// path is being parsed, so including it again is a cycle
+    pub fn enter(&mut self, path: &Path) {
+        if let Ok(full) = fs::canonicalize(path) {
+            self.stack.push(full);
+        }
+    }
+
+    fn error(&mut self, source: usize, span: std::ops::Range<usize>, error: anyhow::Error) {
+        self.errors.push(BCFError {
+            kind: BCFErrorKind::Syntax,
+            key: String::new(),
+            source,
+            span: Some(span),
+            error,
+        });
+    }
+}
@@ -0,0 +1,179 @@
+#![allow(non_snake_case)]
+//! #[derive(BCFConfig)], see BCF::BCFConfig
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{
+    parse_macro_input, spanned::Spanned, Data, DeriveInput, Expr, ExprLit, Fields, Lit, LitStr,
+    Type,
+};
+
+enum FieldDefault {
+    /// the key has to be there
+    None,
+    /// Default::default() of the field
+    Trait,
+    Expr(Expr),
+}
+
+struct FieldAttrs {
+    rename: Option<LitStr>,
+    default: FieldDefault,
+    /// Some if the field is a BCFConfig itself, its keys start with this
+    prefix: Option<LitStr>,
+}
+
+fn field_attrs(field: &syn::Field) -> syn::Result<FieldAttrs> {
+    let mut attrs = FieldAttrs {
+        rename: None,
+        default: FieldDefault::None,
+        prefix: None,
+    };
+    for attr in field.attrs.iter().filter(|a| a.path().is_ident("bcf")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("rename") {
+                attrs.rename = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("default") {
+                attrs.default = match meta.input.peek(syn::Token![=]) {
+                    true => FieldDefault::Expr(meta.value()?.parse()?),
+                    false => FieldDefault::Trait,
+                };
+            } else if meta.path.is_ident("prefix") {
+                attrs.prefix = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("nested") {
+                let name = field.ident.as_ref().expect("named field");
+                attrs.prefix = Some(LitStr::new(&format!("{}_", name), meta.path.span()));
+            } else {
+                return Err(meta.error("expected rename, default, prefix or nested"));
+            }
+            Ok(())
+        })?;
+    }
+    Ok(attrs)
+}
+
+/// Option<T> fields are None when the key is missing
+fn is_option(ty: &Type) -> bool {
+    let Type::Path(path) = ty else {
+        return false;
+    };
+    path.path
+        .segments
+        .last()
+        .is_some_and(|segment| segment.ident == "Option")
+}
+
+/// string literals become whatever the field is with Into, so `default = "abc"` works for Strings
+fn default_expr(expr: &Expr) -> TokenStream2 {
+    match expr {
+        Expr::Lit(ExprLit {
+            lit: Lit::Str(_), ..
+        }) => quote!(::core::convert::Into::into(#expr)),
+        expr => quote!(#expr),
+    }
+}
+
+fn derive(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    let mut container_default = false;
+    for attr in input.attrs.iter().filter(|a| a.path().is_ident("bcf")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("default") {
+                container_default = true;
+                Ok(())
+            } else {
+                Err(meta.error("expected default"))
+            }
+        })?;
+    }
+    let Data::Struct(data) = &input.data else {
+        return Err(syn::Error::new(
+            input.span(),
+            "BCFConfig can only be derived for structs",
+        ));
+    };
+    let Fields::Named(fields) = &data.fields else {
+        return Err(syn::Error::new(
+            input.span(),
+            "BCFConfig needs named fields",
+        ));
+    };
+    let mut reads = Vec::new();
+    let mut inits = Vec::new();
+    for field in &fields.named {
+        let ident = field.ident.as_ref().expect("named field");
+        // the values are read into locals first, so they can't be called conf or prefix
+        let local = format_ident!("__bcf_{}", ident);
+        let ty = &field.ty;
+        let attrs = field_attrs(field)?;
+        let key = attrs
+            .rename
+            .map(|r| r.value())
+            .unwrap_or_else(|| ident.to_string());
+        inits.push(quote!(#ident: #local?));
+        if let Some(prefix) = attrs.prefix {
+            reads.push(quote! {
+                let #local = <#ty as ::BCF::BCFConfig>::collect_bcf(conf, &format!("{}{}", prefix, #prefix), errors);
+            });
+            continue;
+        }
+        let read = if is_option(ty) {
+            quote! {
+                if conf.has(&key) {
+                    conf.collect::<#ty>(&key, errors)
+                } else {
+                    Some(None)
+                }
+            }
+        } else {
+            let default = match attrs.default {
+                FieldDefault::Expr(expr) => Some(default_expr(&expr)),
+                FieldDefault::Trait => Some(quote!(::core::default::Default::default())),
+                FieldDefault::None if container_default => Some(quote!(defaults.#ident)),
+                FieldDefault::None => None,
+            };
+            match default {
+                Some(default) => quote! {
+                    if conf.has(&key) {
+                        conf.collect::<#ty>(&key, errors)
+                    } else {
+                        Some(#default)
+                    }
+                },
+                None => quote!(conf.collect::<#ty>(&key, errors)),
+            }
+        };
+        reads.push(quote! {
+            let #local: Option<#ty> = {
+                let key = format!("{}{}", prefix, #key);
+                #read
+            };
+        });
+    }
+    let defaults = container_default
+        .then(|| quote!(let defaults = <Self as ::core::default::Default>::default();));
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::BCF::BCFConfig for #name #ty_generics #where_clause {
+            fn collect_bcf(
+                conf: &::BCF::RawConfig,
+                prefix: &str,
+                errors: &mut Vec<::BCF::BCFError>,
+            ) -> Option<Self> {
+                #defaults
+                #(#reads)*
+                Some(Self {
+                    #(#inits,)*
+                })
+            }
+        }
+    })
+}
+
+#[proc_macro_derive(BCFConfig, attributes(bcf))]
+pub fn derive_bcf_config(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    derive(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
@@ -0,0 +1,273 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+};
+
+use parking_lot::RwLock;
+use serde::Serialize;
+use time::OffsetDateTime;
+
+/// how many failures are kept around for /admin/audit, oldest are dropped first
+const MAX_ENTRIES: usize = 1000;
+/// failed attempts from one ip within FAILURE_WINDOW before it gets locked out
+pub const MAX_FAILURES: usize = 10;
+pub const FAILURE_WINDOW: time::Duration = time::Duration::minutes(10);
+pub const LOCKOUT: time::Duration = time::Duration::minutes(15);
+/// how many ips are counted or locked out at once, past this the ones that expired are dropped
+const MAX_TRACKED: usize = 10_000;
+/// and at most this often, so a flood of ips doesn't make every failure walk all of them
+const SWEEP_EVERY: time::Duration = time::Duration::minutes(1);
+
+/// one request that had a key, but not a good one
+#[derive(Serialize, Debug, Clone)]
+pub struct AuthFailure {
+    #[serde(with = "time::serde::rfc3339")]
+    pub at: OffsetDateTime,
+    /// None if rocket couldn't tell where the request came from
+    pub ip: Option<IpAddr>,
+    pub method: String,
+    pub route: String,
+    /// the status that was sent back, 401 for bad keys, 403 for missing scopes, 429 while locked out
+    pub status: u16,
+    pub reason: String,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Lockout {
+    pub ip: IpAddr,
+    #[serde(with = "time::serde::rfc3339")]
+    pub until: OffsetDateTime,
+}
+
+/// failed authentications and the ips that are locked out because of them
+#[derive(Debug, Default)]
+pub struct AuthAudit {
+    log: RwLock<VecDeque<AuthFailure>>,
+    /// when each ip failed recently, only bad keys count
+    recent: RwLock<HashMap<IpAddr, Vec<OffsetDateTime>>>,
+    /// when recent was last swept of failures that are out of the window
+    swept: RwLock<Option<OffsetDateTime>>,
+    lockouts: RwLock<HashMap<IpAddr, OffsetDateTime>>,
+}
+
+impl AuthAudit {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// when the lockout of ip ends, if it is locked out right now
+    pub fn locked_until(&self, ip: IpAddr, now: OffsetDateTime) -> Option<OffsetDateTime> {
+        let until = self.lockouts.read().get(&ip).copied()?;
+        if until > now {
+            return Some(until);
+        }
+        let mut lockouts = self.lockouts.write();
+        if lockouts.get(&ip).is_some_and(|until| *until <= now) {
+            lockouts.remove(&ip);
+        }
+        None
+    }
+    /// logs the failure, and locks the ip out if it was a bad key and there were too many of them.
+    /// returns true if the ip just got locked out
+    pub fn record(&self, failure: AuthFailure) -> bool {
+        tracing::warn!(
+            ip = %failure
+                .ip
+                .map(|ip| ip.to_string())
+                .unwrap_or_else(|| "unknown".to_string()),
+            method = %failure.method,
+            route = %failure.route,
+            status = failure.status,
+            reason = %failure.reason,
+            "Auth failure"
+        );
+        let mut locked = false;
+        if let (Some(ip), 401) = (failure.ip, failure.status) {
+            locked = self.count(ip, failure.at);
+        }
+        let mut log = self.log.write();
+        if log.len() >= MAX_ENTRIES {
+            log.pop_front();
+        }
+        log.push_back(failure);
+        locked
+    }
+    /// counts a bad key against ip, locks it out if that was one too many
+    fn count(&self, ip: IpAddr, at: OffsetDateTime) -> bool {
+        let mut recent = self.recent.write();
+        if recent.len() >= MAX_TRACKED && !recent.contains_key(&ip) {
+            let mut swept = self.swept.write();
+            if swept.is_none_or(|swept| at - swept >= SWEEP_EVERY) {
+                *swept = Some(at);
+                recent.retain(|_, times| {
+                    times.retain(|t| at - *t < FAILURE_WINDOW);
+                    !times.is_empty()
+                });
+            }
+            if recent.len() >= MAX_TRACKED {
+                // only logged, the ips that are already being counted keep being counted
+                return false;
+            }
+        }
+        let times = recent.entry(ip).or_default();
+        times.retain(|t| at - *t < FAILURE_WINDOW);
+        times.push(at);
+        if times.len() < MAX_FAILURES {
+            return false;
+        }
+        recent.remove(&ip);
+        let mut lockouts = self.lockouts.write();
+        if lockouts.len() >= MAX_TRACKED {
+            lockouts.retain(|_, until| *until > at);
+            if lockouts.len() >= MAX_TRACKED {
+                if let Some(soonest) = lockouts.iter().min_by_key(|(_, until)| **until) {
+                    let soonest = *soonest.0;
+                    lockouts.remove(&soonest);
+                }
+            }
+        }
+        lockouts.insert(ip, at + LOCKOUT);
+        tracing::warn!(%ip, until = %(at + LOCKOUT), "Locked out");
+        true
+    }
+    /// a good key clears the failures of the ip, so the odd typo doesn't add up
+    pub fn record_success(&self, ip: Option<IpAddr>) {
+        if let Some(ip) = ip {
+            self.recent.write().remove(&ip);
+        }
+    }
+    /// newest first
+    pub fn failures(&self, ip: Option<IpAddr>, limit: usize) -> Vec<AuthFailure> {
+        self.log
+            .read()
+            .iter()
+            .rev()
+            .filter(|f| ip.is_none() || f.ip == ip)
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+    /// the lockouts that haven't ended yet, ending soonest first
+    pub fn lockouts(&self, now: OffsetDateTime) -> Vec<Lockout> {
+        let mut lockouts = self.lockouts.write();
+        lockouts.retain(|_, until| *until > now);
+        let mut lockouts: Vec<Lockout> = lockouts
+            .iter()
+            .map(|(ip, until)| Lockout {
+                ip: *ip,
+                until: *until,
+            })
+            .collect();
+        lockouts.sort_by_key(|l| (l.until, l.ip));
+        lockouts
+    }
+    /// returns false if the ip wasn't locked out
+    pub fn unlock(&self, ip: IpAddr) -> bool {
+        self.recent.write().remove(&ip);
+        self.lockouts.write().remove(&ip).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn failure(ip: &str, at: OffsetDateTime, status: u16) -> AuthFailure {
+        AuthFailure {
+            at,
+            ip: Some(ip.parse().unwrap()),
+            method: "GET".to_string(),
+            route: "/test".to_string(),
+            status,
+            reason: "Invalid key!".to_string(),
+        }
+    }
+
+    #[test]
+    fn locks_out_after_repeated_failures() {
+        let audit = AuthAudit::new();
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let start = OffsetDateTime::UNIX_EPOCH;
+        for i in 0..MAX_FAILURES - 1 {
+            assert!(!audit.record(failure(
+                "10.0.0.1",
+                start + time::Duration::seconds(i as i64),
+                401
+            )));
+            // missing scopes and other ips don't count
+            audit.record(failure("10.0.0.1", start, 403));
+            audit.record(failure("10.0.0.2", start, 401));
+        }
+        assert!(audit.locked_until(ip, start).is_none());
+        assert!(audit.record(failure("10.0.0.1", start + time::Duration::minutes(1), 401)));
+        let until = audit.locked_until(ip, start).unwrap();
+        assert_eq!(until, start + time::Duration::minutes(1) + LOCKOUT);
+        assert_eq!(audit.lockouts(start).len(), 1);
+        assert!(audit.locked_until(ip, until).is_none());
+        assert!(audit.lockouts(until).is_empty());
+
+        assert_eq!(audit.failures(Some(ip), 5).len(), 5);
+        assert_eq!(
+            audit.failures(None, usize::MAX).len(),
+            3 * (MAX_FAILURES - 1) + 1
+        );
+        assert_eq!(
+            audit.failures(None, 1)[0].at,
+            start + time::Duration::minutes(1)
+        );
+    }
+
+    #[test]
+    fn failures_expire_and_success_resets() {
+        let audit = AuthAudit::new();
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let start = OffsetDateTime::UNIX_EPOCH;
+        for i in 0..MAX_FAILURES - 1 {
+            audit.record(failure(
+                "10.0.0.1",
+                start + time::Duration::seconds(i as i64),
+                401,
+            ));
+        }
+        // the first ones are out of the window by now
+        assert!(!audit.record(failure("10.0.0.1", start + FAILURE_WINDOW, 401)));
+        audit.record_success(Some(ip));
+        for _ in 0..MAX_FAILURES - 1 {
+            assert!(!audit.record(failure("10.0.0.1", start + FAILURE_WINDOW, 401)));
+        }
+        assert!(audit.locked_until(ip, start + FAILURE_WINDOW).is_none());
+        assert!(audit.record(failure("10.0.0.1", start + FAILURE_WINDOW, 401)));
+        assert!(audit.unlock(ip));
+        assert!(!audit.unlock(ip));
+        assert!(audit.locked_until(ip, start + FAILURE_WINDOW).is_none());
+    }
+
+    #[test]
+    fn expired_entries_are_dropped() {
+        let audit = AuthAudit::new();
+        let start = OffsetDateTime::UNIX_EPOCH;
+        let ip = |i: u32| IpAddr::V4(i.into());
+        for i in 0..MAX_FAILURES {
+            audit.record(failure(
+                "10.0.0.1",
+                start + time::Duration::seconds(i as i64),
+                401,
+            ));
+        }
+        // the failures that led to a lockout aren't kept
+        assert!(audit.recent.read().is_empty());
+        let until = audit.locked_until(ip(0x0a000001), start).unwrap();
+        assert!(audit.locked_until(ip(0x0a000001), until).is_none());
+        assert!(audit.lockouts.read().is_empty());
+
+        for i in 0..MAX_TRACKED as u32 {
+            audit.record(failure(&ip(i).to_string(), start, 401));
+        }
+        assert_eq!(audit.recent.read().len(), MAX_TRACKED);
+        // full of ips that are still in the window, so new ones aren't counted
+        assert!(!audit.count(ip(u32::MAX), start));
+        assert_eq!(audit.recent.read().len(), MAX_TRACKED);
+        // once theirs are out of it they make room
+        assert!(!audit.count(ip(u32::MAX), start + FAILURE_WINDOW));
+        assert_eq!(audit.recent.read().len(), 1);
+    }
+}
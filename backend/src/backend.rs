@@ -1,93 +1,201 @@
+use crate::reload::LiveConfig;
 use crate::{
-    northwood::SlServer,
-    northwood::{Player, SLResponse},
+    northwood::{Player, RateLimited, SlServer},
+    source::ServerSource,
+    state::ServerState,
 };
 use futures::future::join_all;
-use lazy_static::lazy_static;
 use lurky::{
     config::LurkyConfig,
     db::{DBPlayer, ManagedDB},
 };
-use parking_lot::RwLock;
 use std::{hash::Hasher, sync::Arc, time::Duration};
-lazy_static! {
-    pub static ref CACHED_NW_REQ: RwLock<Vec<Option<SLResponse>>> = RwLock::new(Vec::new());
+use tracing::{debug, error, info, instrument, trace, warn};
+
+pub(crate) fn parse_sources(conf: &LurkyConfig) -> Vec<Box<dyn ServerSource>> {
+    conf.servers
+        .iter()
+        .map(|s| Box::new(SlServer::new(s, &conf.nw_api_url)) as Box<dyn ServerSource>)
+        .collect()
+}
+
+/// play time is counted in whole seconds, and the interval can't be zero
+fn refresh_secs(conf: &LurkyConfig) -> u64 {
+    conf.refresh_cooldown.as_secs().max(1)
+}
+
+fn refresh_interval(conf: &LurkyConfig) -> rocket::tokio::time::Interval {
+    let mut intv = rocket::tokio::time::interval(Duration::from_secs(refresh_secs(conf)));
+    intv.set_missed_tick_behavior(rocket::tokio::time::MissedTickBehavior::Delay);
+    intv
 }
 
 /// this function runs in a seperate thread, it really shouldnt return
-pub async fn backend(conf: Arc<LurkyConfig>, db: Arc<ManagedDB>) {
-    let refresh = conf.refresh_cooldown;
-    println!("Backend: Refresh cooldown: {}", refresh);
-    println!("Parsing servers...");
-    let servers = conf
-        .servers
-        .iter()
-        .map(|s| SlServer::parse(s))
-        .collect::<Vec<SlServer>>();
-    println!("Parsed {} servers", servers.len());
-    for _ in servers.iter() {
-        CACHED_NW_REQ.write().push(None);
+pub async fn backend(live: Arc<LiveConfig>, db: Arc<ManagedDB>, state: Arc<ServerState>) {
+    let mut conf = live.get();
+    let mut sources = parse_sources(&conf);
+    info!(
+        refresh = refresh_secs(&conf),
+        servers = sources.len(),
+        "Starting the backend"
+    );
+    let mut intv = refresh_interval(&conf);
+    if let Err(e) = db.end_stale_sessions().await {
+        error!(error = %e, "Failed to close stale sessions");
     }
-    let mut intv = rocket::tokio::time::interval(Duration::from_secs(refresh));
-    intv.set_missed_tick_behavior(rocket::tokio::time::MissedTickBehavior::Delay);
-    let mut old_plr_list: Vec<Player> = vec![];
-    let mut alone_players: Vec<String> = vec![];
+    let mut poller = Poller::default();
+    let mut changes = live.subscribe();
     loop {
         // do shit
-        intv.tick().await;
-        println!("Backend refresh!");
-        let mut player_list: Vec<Player> = vec![];
-        for (id, server) in servers.iter().enumerate() {
-            let resp = server.get().await;
-            println!("{:#?}", resp);
-            if let Ok(resp) = resp {
-                CACHED_NW_REQ.write()[id] = Some(resp.clone());
-                for server in resp.servers {
-                    if !server.online {
-                        continue;
-                    }
-                    if server.players_list.len() == 1
-                    {
-                        println!("Player is alone, pushing to alone_players");
-                        alone_players.push(server.players_list[0].id.clone());
-                    }
-                    player_list.extend(server.players_list);
+        rocket::tokio::select! {
+            _ = intv.tick() => {}
+            Ok(()) = changes.changed() => {
+                let latest = Arc::clone(&changes.borrow_and_update());
+                sources = parse_sources(&latest);
+                info!(servers = sources.len(), "Using the reloaded config");
+                state.reconfigure(
+                    sources.iter().map(|s| s.id()),
+                    time::Duration::seconds(refresh_secs(&latest) as i64),
+                );
+                // a new interval ticks right away, so the new cooldown starts with a refresh
+                if refresh_secs(&latest) != refresh_secs(&conf) {
+                    info!(refresh = refresh_secs(&latest), "Refresh cooldown changed");
+                    intv = refresh_interval(&latest);
                 }
+                conf = latest;
+                continue;
             }
         }
-        // do the db things
+        poller
+            .tick(&sources, &state, &db, refresh_secs(&conf))
+            .await;
+    }
+}
+
+/// what the backend loop remembers between ticks
+#[derive(Default)]
+pub struct Poller {
+    // (server id, port, player)
+    old_plr_list: Vec<(u64, u16, Player)>,
+    /// how many ticks there were, so the log lines of one can be told apart
+    ticks: u64,
+}
 
+impl Poller {
+    /// fetches the servers that are due and puts whoever is online into the db
+    #[instrument(name = "tick", skip_all, fields(tick = self.ticks))]
+    pub async fn tick(
+        &mut self,
+        sources: &[Box<dyn ServerSource>],
+        state: &ServerState,
+        db: &Arc<ManagedDB>,
+        refresh: u64,
+    ) {
+        self.ticks += 1;
+        let mut player_list: Vec<(u64, u16, Player)> = vec![];
+        let mut alone_players: Vec<String> = vec![];
+        for source in sources.iter() {
+            fetch(source.as_ref(), state).await;
+        }
+        // servers that failed keep their last players until they go stale
+        for resp in state.live_responses() {
+            for server in resp.servers {
+                if !server.online {
+                    continue;
+                }
+                if server.players_list.len() == 1 {
+                    alone_players.push(server.players_list[0].id.clone());
+                }
+                player_list.extend(
+                    server
+                        .players_list
+                        .into_iter()
+                        .map(|p| (server.id, server.port, p)),
+                );
+            }
+        }
+        // do the db things
+        // close the sessions of anyone who left (or hopped servers) first
+        let now = time::OffsetDateTime::now_utc();
         join_all(
-            player_list
+            self.old_plr_list
                 .iter()
-                .map(|e| update_player(e, Arc::clone(&db), refresh, old_plr_list.clone(), alone_players.clone())),
+                .filter(|(sid, _, p)| !player_list.iter().any(|(s, _, e)| s == sid && e.id == p.id))
+                .filter_map(|(_, _, p)| parse_player_id(p))
+                .map(|(id, _)| end_session(id, Arc::clone(db), now)),
         )
         .await;
-        old_plr_list = player_list;
-        alone_players.clear();
+
+        join_all(player_list.iter().map(|(sid, port, e)| {
+            update_player(
+                e,
+                *sid,
+                *port,
+                Arc::clone(db),
+                refresh,
+                self.old_plr_list.clone(),
+                alone_players.clone(),
+            )
+        }))
+        .await;
+        debug!(players = player_list.len(), "Updated the players");
+        self.old_plr_list = player_list;
     }
 }
 
-async fn update_player(
-    player: &Player,
-    db: Arc<ManagedDB>,
-    refresh: u64,
-    old_plr_list: Vec<Player>,
-    alone_players_copy: Vec<String>
-) {
-    // identify id
-    let mut id_parts = player.id.split("@");
+/// asks source for its players if it isn't cooling down, the response ends up in state
+#[instrument(name = "server", skip_all, fields(id = source.id()))]
+async fn fetch(source: &dyn ServerSource, state: &ServerState) {
+    if !state.due(source.id(), time::OffsetDateTime::now_utc()) {
+        debug!("Cooling down, using the last response");
+        return;
+    }
+    let resp = source.get().await;
+    let fetched = time::OffsetDateTime::now_utc();
+    match resp {
+        Ok(resp) => {
+            debug!(
+                online = resp.servers.iter().filter(|s| s.online).count(),
+                players = resp
+                    .servers
+                    .iter()
+                    .map(|s| s.players_list.len())
+                    .sum::<usize>(),
+                "Fetched"
+            );
+            state.record_success(source.id(), resp, fetched)
+        }
+        Err(e) => {
+            let retry_after = e
+                .downcast_ref::<RateLimited>()
+                .and_then(|r| r.retry_after)
+                .map(|secs| time::Duration::seconds(secs as i64));
+            warn!(error = %e, "Failed to fetch the server");
+            state.record_error(source.id(), e.to_string(), retry_after, fetched);
+        }
+    }
+}
+
+async fn end_session(id: u64, db: Arc<ManagedDB>, at: time::OffsetDateTime) {
+    if let Err(e) = db.end_session(id, at).await {
+        error!(player = id, error = %e, "Failed to end the session");
+    }
+}
+
+/// turns a northwood player id into our db id and their nickname
+fn parse_player_id(player: &Player) -> Option<(u64, String)> {
+    let mut id_parts = player.id.split('@');
     let raw_id = id_parts.next();
     let identif = id_parts.next();
     if raw_id.is_none() || identif.is_none() {
-        eprintln!("Invalid player id: {}", player.id);
-        return;
+        warn!(player = %player.id, "Invalid player id");
+        return None;
     }
     let raw_id = raw_id.unwrap();
     let identif = identif.unwrap();
 
-    let (id, nick) = match identif {
-        "steam" => (
+    match identif {
+        "steam" => Some((
             raw_id
                 .parse::<u64>()
                 .expect("steam player to have valid u64 id"),
@@ -95,50 +203,77 @@ async fn update_player(
                 .nickname
                 .clone()
                 .expect("Steam player to have nickname"),
-        ),
+        )),
         "northwood" => {
             let mut hasher = std::collections::hash_map::DefaultHasher::new();
             hasher.write(raw_id.as_bytes());
-            (hasher.finish(), raw_id.to_string())
+            Some((hasher.finish(), raw_id.to_string()))
         }
         _ => {
-            eprintln!("Invalid indicator: {}", identif);
-            return;
+            warn!(player = %player.id, "Invalid indicator");
+            None
         }
+    }
+}
+
+#[instrument(name = "player", skip_all, fields(server = server_id, player = %player.id))]
+async fn update_player(
+    player: &Player,
+    server_id: u64,
+    port: u16,
+    db: Arc<ManagedDB>,
+    refresh: u64,
+    old_plr_list: Vec<(u64, u16, Player)>,
+    alone_players_copy: Vec<String>,
+) {
+    let Some((id, nick)) = parse_player_id(player) else {
+        return;
     };
 
-    println!("{}: {}", id, nick);
-    if (db.has_player(id).await).unwrap_or(false) {
+    let exists = match db.has_player(id).await {
+        Ok(exists) => exists,
+        Err(e) => {
+            // trying to add them would only fail too
+            error!(error = %e, "Failed to check the player");
+            return;
+        }
+    };
+    if exists {
         // update the player
-        println!("Updating player: {} ({})", nick, id);
-        let mut dbplayer = db.get_player(id).await.unwrap();
+        trace!(nick, "Updating the player");
+        let mut dbplayer = match db.get_player(id).await {
+            Ok(p) => p,
+            Err(e) => {
+                error!(error = %e, "Failed to get the player");
+                return;
+            }
+        };
         if dbplayer.last_nickname != nick {
             dbplayer.nicknames.push(nick.clone());
         }
         dbplayer.last_nickname = nick;
         dbplayer.last_seen = time::OffsetDateTime::now_utc();
         //try checking for if the player is alone
-        if alone_players_copy.contains(&player.id)
-        {
-            println!("Player is alone, not adding time");
-        }
-        else
-        {
-            dbplayer.play_time = dbplayer.play_time + time::Duration::seconds(refresh as i64);
+        if alone_players_copy.contains(&player.id) {
+            trace!("Alone, not adding time");
+        } else {
+            dbplayer.play_time += time::Duration::seconds(refresh as i64);
         }
-        if !old_plr_list.iter().any(|e| e.id == player.id) {
+        if !old_plr_list.iter().any(|(_, _, e)| e.id == player.id) {
             // this player just logged in
             dbplayer.time_online = time::Duration::seconds(refresh as i64);
             dbplayer.login_amt += 1;
         } else {
-            dbplayer.time_online = dbplayer.time_online + time::Duration::seconds(refresh as i64);
+            dbplayer.time_online += time::Duration::seconds(refresh as i64);
         }
         //player.time_online = player.time_online + time::Duration::seconds(refresh as i64);
         //player.login_amt += 1;
-        db.update_player(dbplayer).await.unwrap();
+        if let Err(e) = db.update_player(dbplayer).await {
+            error!(error = %e, "Failed to update the player");
+        }
     } else {
         // add the player!
-        println!("Adding player: {} ({})", nick, id);
+        debug!(nick, "Adding a new player");
         let r = db
             .create_player(DBPlayer {
                 id,
@@ -153,7 +288,109 @@ async fn update_player(
             })
             .await;
         if let Err(e) = r {
-            eprintln!("Error adding player: {}", e);
+            error!(error = %e, "Failed to add the player");
         }
     }
+    let now = time::OffsetDateTime::now_utc();
+    // they either just joined or hopped servers
+    let joined = !old_plr_list
+        .iter()
+        .any(|(sid, _, e)| *sid == server_id && e.id == player.id);
+    if joined {
+        if let Err(e) = db.start_session(id, server_id, now).await {
+            error!(error = %e, "Failed to start the session");
+        }
+    }
+    let play_time = if alone_players_copy.contains(&player.id) {
+        time::Duration::ZERO
+    } else {
+        time::Duration::seconds(refresh as i64)
+    };
+    if let Err(e) = db
+        .record_server_stats(id, server_id, port, now, play_time, joined)
+        .await
+    {
+        error!(error = %e, "Failed to record the server stats");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::scripted::{response, ScriptedSource};
+    use lurky::db::mem::MemoryDB;
+
+    const A: &str = "76561198000000001@steam";
+    const B: &str = "76561198000000002@steam";
+    const REFRESH: u64 = 60;
+
+    async fn run(script: Vec<Result<crate::northwood::SLResponse, String>>) -> Arc<ManagedDB> {
+        let db: Arc<ManagedDB> = Arc::new(Box::new(MemoryDB::new()));
+        let state = ServerState::new([7], time::Duration::seconds(REFRESH as i64));
+        let ticks = script.len();
+        let sources: Vec<Box<dyn ServerSource>> = vec![Box::new(ScriptedSource::new(7, script))];
+        let mut poller = Poller::default();
+        for _ in 0..ticks {
+            poller.tick(&sources, &state, &db, REFRESH).await;
+        }
+        db
+    }
+
+    #[rocket::async_test]
+    async fn players_over_ticks() {
+        let db = run(vec![
+            Ok(response(&[(7, 7777, &[A, B])])),
+            Ok(response(&[(7, 7777, &[A, B])])),
+            // b left, a is alone now
+            Ok(response(&[(7, 7777, &[A])])),
+            // b is back on the other server
+            Ok(response(&[(7, 7777, &[A]), (8, 7778, &[B])])),
+        ])
+        .await;
+        let minute = time::Duration::seconds(REFRESH as i64);
+
+        let a = db.get_player(76561198000000001).await.unwrap();
+        assert_eq!(a.last_nickname, format!("nick {}", A));
+        assert_eq!(a.login_amt, 1);
+        assert_eq!(a.play_time, minute * 2);
+        assert_eq!(a.time_online, minute * 4);
+        assert_eq!(db.get_sessions(a.id, 10, 0).await.unwrap().len(), 1);
+
+        let b = db.get_player(76561198000000002).await.unwrap();
+        assert_eq!(b.login_amt, 2);
+        assert_eq!(b.play_time, minute * 2);
+        assert_eq!(b.time_online, minute);
+        let sessions = db.get_sessions(b.id, 10, 0).await.unwrap();
+        assert_eq!(
+            sessions
+                .iter()
+                .map(|s| (s.server_id, s.ended.is_some()))
+                .collect::<Vec<_>>(),
+            vec![(8, false), (7, true)]
+        );
+
+        let stats = db.get_server_stats(b.id).await.unwrap();
+        assert_eq!(
+            stats
+                .iter()
+                .map(|s| (s.server_id, s.port, s.play_time, s.login_amt))
+                .collect::<Vec<_>>(),
+            vec![(7, 7777, minute * 2, 1), (8, 7778, time::Duration::ZERO, 1)]
+        );
+    }
+
+    #[rocket::async_test]
+    async fn failed_fetch_keeps_players() {
+        let db = run(vec![
+            Ok(response(&[(7, 7777, &[A, B])])),
+            Err("Northwood is down".to_string()),
+        ])
+        .await;
+        // still online as far as we know, so nobody logged out
+        let a = db.get_player(76561198000000001).await.unwrap();
+        assert_eq!(a.time_online, time::Duration::seconds(REFRESH as i64 * 2));
+        let sessions = db.get_sessions(a.id, 10, 0).await.unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert!(sessions[0].ended.is_none());
+    }
 }
@@ -0,0 +1,215 @@
+use std::{path::PathBuf, sync::Arc};
+
+use crate::reload::LiveConfig;
+use rocket::{
+    catch,
+    fairing::{Fairing, Info, Kind},
+    http::{ContentType, MediaType, Status},
+    response::{Responder, Response},
+    serde::json::Json,
+    tokio::fs,
+    Request,
+};
+use serde::Serialize;
+
+/// the id of a request, sent back as X-Request-Id and in error bodies so errors can be found in the logs.
+/// a sane X-Request-Id from the client (or a proxy) is kept, otherwise a random one is made
+pub struct RequestId(pub String);
+
+impl RequestId {
+    pub fn of<'r>(req: &'r Request<'_>) -> &'r str {
+        &req.local_cache(|| {
+            let incoming = req.headers().get_one("X-Request-Id").filter(|id| {
+                !id.is_empty()
+                    && id.len() <= 64
+                    && id
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            });
+            RequestId(match incoming {
+                Some(id) => id.to_string(),
+                None => format!("{:016x}", rand::random::<u64>()),
+            })
+        })
+        .0
+    }
+}
+
+pub struct RequestIds;
+
+#[rocket::async_trait]
+impl Fairing for RequestIds {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request ids",
+            kind: Kind::Response,
+        }
+    }
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        res.set_raw_header("X-Request-Id", RequestId::of(req).to_string());
+    }
+}
+
+/// why a guard failed, so the catcher can say more than the status does
+pub struct ErrorReason(pub Option<String>);
+
+/// how long a 429 should tell the client to wait, the catcher turns it into Retry-After
+pub struct RetryAfter(pub Option<std::time::Duration>);
+
+#[derive(Serialize)]
+pub struct ErrorBody {
+    pub status: u16,
+    pub reason: String,
+    pub request_id: String,
+}
+
+pub enum ErrorResponse {
+    Json(ErrorBody),
+    Text(ErrorBody),
+    Cat(ErrorBody, Vec<u8>),
+}
+
+impl ErrorResponse {
+    fn body(&self) -> &ErrorBody {
+        match self {
+            ErrorResponse::Json(body) | ErrorResponse::Text(body) | ErrorResponse::Cat(body, _) => {
+                body
+            }
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ErrorResponse {
+    fn respond_to(self, req: &'r Request<'_>) -> rocket::response::Result<'static> {
+        let status = Status::from_code(self.body().status).unwrap_or(Status::InternalServerError);
+        let mut res = match self {
+            ErrorResponse::Json(body) => Response::build_from(Json(body).respond_to(req)?),
+            ErrorResponse::Text(body) => Response::build_from(
+                format!(
+                    "{} {} (request {})",
+                    body.status, body.reason, body.request_id
+                )
+                .respond_to(req)?,
+            ),
+            ErrorResponse::Cat(_, image) => {
+                Response::build_from((ContentType::JPEG, image).respond_to(req)?)
+            }
+        };
+        res.status(status);
+        if let Some(wait) = req.local_cache(|| RetryAfter(None)).0 {
+            // rounded up, so retrying right after doesn't hit an empty bucket again
+            res.raw_header("Retry-After", wait.as_secs_f64().ceil().to_string());
+        }
+        res.ok()
+    }
+}
+
+/// the http.cat image for the status from http_cat_dir, downloaded into it the first time if it isn't there
+async fn http_cat(dir: &str, status: Status) -> Option<Vec<u8>> {
+    let path = PathBuf::from(dir).join(format!("{}.jpg", status.code));
+    if let Ok(image) = fs::read(&path).await {
+        return Some(image);
+    }
+    let image = reqwest::get(format!("https://http.cat/{}", status.code))
+        .await
+        .and_then(|r| r.error_for_status())
+        .ok()?
+        .bytes()
+        .await
+        .ok()?
+        .to_vec();
+    if let Err(e) = fs::write(&path, &image).await {
+        tracing::warn!(path = %path.display(), error = %e, "Failed to cache the cat");
+    }
+    Some(image)
+}
+
+/// json unless the client would rather have text, or a cat if they asked for an image and cats are on
+#[catch(default)]
+pub async fn default_error_catcher(status: Status, req: &Request<'_>) -> ErrorResponse {
+    let body = ErrorBody {
+        status: status.code,
+        reason: req
+            .local_cache(|| ErrorReason(None))
+            .0
+            .clone()
+            .unwrap_or_else(|| status.reason_lossy().to_string()),
+        request_id: RequestId::of(req).to_string(),
+    };
+    let preferred = req
+        .accept()
+        .map(|a| a.preferred().media_type().clone())
+        .unwrap_or(MediaType::JSON);
+    if preferred.top() == "image" {
+        let conf = req
+            .rocket()
+            .state::<Arc<LiveConfig>>()
+            .expect("The config to be present??")
+            .get();
+        if !conf.http_cat_dir.is_empty() {
+            if let Some(image) = http_cat(&conf.http_cat_dir, status).await {
+                return ErrorResponse::Cat(body, image);
+            }
+        }
+    }
+    if preferred.is_plain() {
+        return ErrorResponse::Text(body);
+    }
+    ErrorResponse::Json(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::{catchers, http::Header, local::asynchronous::Client};
+
+    async fn client() -> Client {
+        let rocket = rocket::build()
+            .register("/", catchers![default_error_catcher])
+            .attach(RequestIds);
+        Client::tracked(rocket).await.unwrap()
+    }
+
+    #[rocket::async_test]
+    async fn errors_are_json_by_default() {
+        let client = client().await;
+        let res = client
+            .get("/nope")
+            .header(Header::new("X-Request-Id", "abc-123"))
+            .dispatch()
+            .await;
+        assert_eq!(res.status(), Status::NotFound);
+        assert_eq!(res.content_type(), Some(ContentType::JSON));
+        assert_eq!(res.headers().get_one("X-Request-Id"), Some("abc-123"));
+        let body: serde_json::Value = res.into_json().await.unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({"status": 404, "reason": "Not Found", "request_id": "abc-123"})
+        );
+    }
+
+    #[rocket::async_test]
+    async fn errors_follow_accept() {
+        let client = client().await;
+        let res = client
+            .get("/nope")
+            .header(rocket::http::Accept::Plain)
+            .header(Header::new("X-Request-Id", "not a valid id"))
+            .dispatch()
+            .await;
+        assert_eq!(res.content_type(), Some(ContentType::Plain));
+        let id = res.headers().get_one("X-Request-Id").unwrap().to_string();
+        assert_ne!(id, "not a valid id");
+        assert_eq!(
+            res.into_string().await.unwrap(),
+            format!("404 Not Found (request {})", id)
+        );
+        // browsers get json too
+        let res = client
+            .get("/nope")
+            .header(rocket::http::Accept::HTML)
+            .dispatch()
+            .await;
+        assert_eq!(res.content_type(), Some(ContentType::JSON));
+    }
+}
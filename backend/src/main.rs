@@ -1,67 +1,129 @@
 use lurky::config::LurkyConfig;
-use rocket::http::ContentType;
+use rocket::catchers;
 use rocket::tokio::spawn;
-use rocket::{catch, catchers};
+mod audit;
 mod backend;
+mod catcher;
 mod northwood;
-use std::path::PathBuf;
+mod ratelimit;
+mod reload;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 mod routes;
-use clap::Parser;
+mod source;
+mod state;
+use clap::{Parser, Subcommand};
 use lurky::db;
 #[derive(Debug, Clone, Parser)]
 #[clap(author, version, about, long_about = None)]
+#[clap(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
-    config: PathBuf,
+    #[clap(subcommand)]
+    command: Option<Command>,
+    #[clap(required = true)]
+    config: Option<PathBuf>,
+    /// override a config value, these win over the file and LURKY_* environment variables
+    #[clap(short, long, value_name = "KEY=VALUE")]
+    set: Vec<String>,
 }
 
-impl Args {
-    fn validate(&self) -> Result<(), String> {
-        if !self.config.exists() {
-            return Err(format!(
-                "Config file does not exist: {}",
-                self.config.display()
-            ));
-        }
-        Ok(())
+#[derive(Debug, Clone, Subcommand)]
+enum Command {
+    /// check the config without starting anything, every problem with it is printed
+    CheckConfig {
+        config: PathBuf,
+        /// override a config value, like it would be when running
+        #[clap(short, long, value_name = "KEY=VALUE")]
+        set: Vec<String>,
+    },
+}
+
+fn validate(config: &Path) -> Result<(), String> {
+    if !config.exists() {
+        return Err(format!("Config file does not exist: {}", config.display()));
     }
+    Ok(())
 }
-#[catch(default)]
-async fn default_error_catcher(
-    status: rocket::http::Status,
-    _: &rocket::Request<'_>,
-) -> (rocket::http::ContentType, (rocket::http::Status, Vec<u8>)) {
-    let http_cat = format!("https://http.cat/{}", status.code);
-    let e = reqwest::get(http_cat).await;
-    if let Ok(e) = e {
-        let bytes = e.bytes().await;
-        if let Ok(bytes) = bytes {
-            return (ContentType::JPEG, (status, bytes.to_vec()));
+
+/// exits with 0 if the config would start, 1 if it wouldn't
+fn check_config(config: &Path, overrides: &[String]) -> ! {
+    if let Err(e) = validate(config) {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+    match LurkyConfig::load(config, overrides) {
+        Ok(_) => {
+            println!("{} is OK", config.display());
+            std::process::exit(0);
+        }
+        Err(report) => {
+            report.eprint();
+            eprintln!("{} problem(s) with the config", report.errors.len());
+            std::process::exit(1);
         }
     }
-    return (ContentType::Text, (status, Vec::new()));
 }
 
 #[rocket::main]
 async fn main() -> Result<(), anyhow::Error> {
     let args = Args::parse();
-    if let Err(e) = args.validate() {
+    if let Some(Command::CheckConfig { config, set }) = &args.command {
+        check_config(config, set);
+    }
+    // clap makes sure it's there without a subcommand
+    let config_path = args.config.expect("config is required");
+    if let Err(e) = validate(&config_path) {
         eprintln!("Error: {}", e);
         std::process::exit(1);
     }
-    let config = Arc::new(LurkyConfig::parse_data(std::fs::File::open(args.config)?));
-    println!("{:?}", config);
+    let config = match LurkyConfig::load(&config_path, &args.set) {
+        Ok(config) => config,
+        Err(report) => {
+            report.eprint();
+            std::process::exit(1);
+        }
+    };
+    let log_level = lurky::logging::init(&config)?;
+    tracing::info!(?config, "Loaded the config");
     let mut db = db::create_db_from_config(&config)?;
     db.setup().await?;
     let db = Arc::new(db);
-    let backend_thread = spawn(backend::backend(Arc::clone(&config), Arc::clone(&db)));
-    let _rocket = rocket::build()
-        .register("/", catchers![default_error_catcher])
+    let ids: Vec<u64> = config.servers.iter().map(|s| s.id).collect();
+    let server_state = Arc::new(state::ServerState::new(
+        ids,
+        time::Duration::seconds(config.refresh_cooldown.as_secs() as i64),
+    ));
+    let limiter = Arc::new(ratelimit::RateLimiter::new(config.rate_limits));
+    // rocket's log lines come through tracing, colors in them would end up in the json
+    let figment = rocket::Config::figment().merge((
+        "cli_colors",
+        config.log_format != lurky::config::LogFormat::Json,
+    ));
+    let config = Arc::new(reload::LiveConfig::new(config));
+    spawn(reload::watch(Arc::clone(&config), config_path, args.set));
+    spawn(reload::follow(
+        Arc::clone(&config),
+        Arc::clone(&limiter),
+        log_level,
+    ));
+    let backend_thread = spawn(backend::backend(
+        Arc::clone(&config),
+        Arc::clone(&db),
+        Arc::clone(&server_state),
+    ));
+    let _rocket = rocket::custom(figment)
+        .register("/", catchers![catcher::default_error_catcher])
+        .attach(catcher::RequestIds)
         .mount("/", routes::basics::routes())
         .mount("/nw", routes::northwood::routes())
         .mount("/query", routes::query::routes())
+        .mount("/flags", routes::flags::routes())
+        .mount("/admin", routes::admin::routes())
         .manage(Arc::clone(&config))
         .manage(Arc::clone(&db))
+        .manage(server_state)
+        .manage(Arc::new(audit::AuthAudit::new()))
+        .manage(limiter)
         .manage(backend_thread)
         .launch()
         .await?;
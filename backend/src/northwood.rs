@@ -1,6 +1,10 @@
 use anyhow::anyhow;
+use async_trait::async_trait;
+use lurky::config::ServerEntry;
 use serde::{Deserialize, Serialize};
 
+use crate::source::ServerSource;
+
 #[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
 #[serde(rename_all = "PascalCase")]
 pub struct SLResponse {
@@ -27,32 +31,75 @@ pub struct Player {
     pub nickname: Option<String>,
 }
 
+/// northwood told us to slow down, retry_after is how long they want us to wait if they said
+#[derive(Debug)]
+pub struct RateLimited {
+    pub retry_after: Option<u64>,
+}
+
+impl std::fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.retry_after {
+            Some(secs) => write!(f, "Rate limited, retry after {}s", secs),
+            None => write!(f, "Rate limited"),
+        }
+    }
+}
+
+impl std::error::Error for RateLimited {}
+
 pub struct SlServer {
     key: String,
     sid: u64,
+    base_url: String,
 }
 
 impl SlServer {
-    pub fn parse(p: &str) -> Self {
-        let mut parts = p.split('|');
-        let sid = parts.next().expect("Invalid server id");
-        let key = parts.next().expect("Invalid key");
-        let sid = sid.parse::<u64>().expect("Unable to parse id");
+    /// base_url is where the northwood api lives, nw_api_url in the config
+    pub fn new(server: &ServerEntry, base_url: &str) -> Self {
         Self {
-            key: key.to_string(),
-            sid,
+            key: server.key.clone(),
+            sid: server.id,
+            base_url: base_url.trim_end_matches('/').to_string(),
         }
     }
     fn api_url(&self) -> String {
-        format!("https://api.scpslgame.com/serverinfo.php?id={}&key={}&list=true&nicknames=true&online=true", self.sid, self.key)
+        format!(
+            "{}/serverinfo.php?id={}&key={}&list=true&nicknames=true&online=true",
+            self.base_url, self.sid, self.key
+        )
+    }
+}
+
+#[async_trait]
+impl ServerSource for SlServer {
+    fn id(&self) -> u64 {
+        self.sid
     }
-    pub async fn get(&self) -> Result<SLResponse, anyhow::Error> {
-        let resp = reqwest::get(self.api_url()).await?.text().await?;
-        println!("{}", resp);
+    async fn get(&self) -> Result<SLResponse, anyhow::Error> {
+        // the url has the key in it, keep it out of errors since those get shown on /nw/status
+        let resp = reqwest::get(self.api_url())
+            .await
+            .map_err(|e| e.without_url())?;
+        if resp.status() == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            let retry_after = resp
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|h| h.to_str().ok())
+                .and_then(|h| h.parse::<u64>().ok());
+            return Err(RateLimited { retry_after }.into());
+        }
+        let resp = resp.text().await.map_err(|e| e.without_url())?;
         let resp: serde_json::Value = serde_json::from_str(&resp)?;
         if resp["Success"].as_bool().unwrap_or(false) {
             let resp: SLResponse = serde_json::from_value(resp)?;
             Ok(resp)
+        } else if let Some(cooldown) = resp["Cooldown"].as_u64() {
+            // errors with a cooldown are them rate limiting us
+            Err(RateLimited {
+                retry_after: Some(cooldown),
+            }
+            .into())
         } else {
             Err(anyhow!(
                 "API returned error: {}",
@@ -61,3 +108,183 @@ impl SlServer {
         }
     }
 }
+
+#[cfg(test)]
+pub mod fake {
+    use std::{collections::VecDeque, net::SocketAddr, sync::Arc};
+
+    use parking_lot::Mutex;
+    use rocket::tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    /// what the fake answers one request with
+    pub struct FakeResponse {
+        pub status: u16,
+        pub headers: Vec<(&'static str, String)>,
+        pub body: String,
+    }
+
+    impl FakeResponse {
+        pub fn json(body: &str) -> Self {
+            Self {
+                status: 200,
+                headers: vec![],
+                body: body.to_string(),
+            }
+        }
+    }
+
+    /// a stand in for the northwood api on localhost, point nw_api_url at url().
+    /// it answers requests with the responses it was given, in order, then with 500s
+    pub struct FakeNorthwood {
+        addr: SocketAddr,
+        /// the path and query of every request it got
+        pub requests: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl FakeNorthwood {
+        pub async fn start(script: Vec<FakeResponse>) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let requests = Arc::new(Mutex::new(Vec::new()));
+            let script = Arc::new(Mutex::new(VecDeque::from(script)));
+            let seen = requests.clone();
+            rocket::tokio::spawn(async move {
+                while let Ok((mut stream, _)) = listener.accept().await {
+                    let mut buf = Vec::new();
+                    let mut chunk = [0u8; 1024];
+                    // only GETs are made, so the request is done at the empty line
+                    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+                        match stream.read(&mut chunk).await {
+                            Ok(0) | Err(_) => break,
+                            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+                        }
+                    }
+                    let head = String::from_utf8_lossy(&buf);
+                    let target = head.split(' ').nth(1).unwrap_or_default().to_string();
+                    seen.lock().push(target);
+                    let resp = script.lock().pop_front().unwrap_or(FakeResponse {
+                        status: 500,
+                        headers: vec![],
+                        body: "Script ran out".to_string(),
+                    });
+                    let mut out = format!(
+                        "HTTP/1.1 {} Fake\r\nContent-Length: {}\r\nConnection: close\r\n",
+                        resp.status,
+                        resp.body.len()
+                    );
+                    for (name, value) in &resp.headers {
+                        out.push_str(&format!("{}: {}\r\n", name, value));
+                    }
+                    out.push_str("\r\n");
+                    out.push_str(&resp.body);
+                    let _ = stream.write_all(out.as_bytes()).await;
+                    let _ = stream.shutdown().await;
+                }
+            });
+            Self { addr, requests }
+        }
+        pub fn url(&self) -> String {
+            format!("http://{}", self.addr)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fake::{FakeNorthwood, FakeResponse};
+    use super::*;
+    use crate::backend::parse_sources;
+    use lurky::config::{LogFormat, LurkyConfig, RateLimits};
+
+    /// the source for server 7 from a config with nw_api_url pointed at url
+    fn source(url: &str) -> Box<dyn ServerSource> {
+        let config = LurkyConfig {
+            servers: vec![ServerEntry {
+                id: 7,
+                key: "secret".to_string(),
+            }],
+            auth_key: String::new(),
+            db_type: "memory".to_string(),
+            db_url: String::new(),
+            refresh_cooldown: std::time::Duration::from_secs(60),
+            nw_api_url: url.to_string(),
+            rate_limits: RateLimits::default(),
+            http_cat_dir: String::new(),
+            log_level: String::new(),
+            log_format: LogFormat::Text,
+            trusted_proxies: vec![],
+        };
+        parse_sources(&config).pop().unwrap()
+    }
+
+    fn retry_after(err: anyhow::Error) -> Option<u64> {
+        err.downcast::<RateLimited>()
+            .expect("a rate limit error")
+            .retry_after
+    }
+
+    #[rocket::async_test]
+    async fn parses_a_listing() {
+        let fake = FakeNorthwood::start(vec![FakeResponse::json(
+            r#"{"Success":true,"Cooldown":15,"Servers":[{"ID":7,"Port":7777,"Online":true,
+                "PlayersList":[{"ID":"76561198000000001@steam","Nickname":"alpha"}]}]}"#,
+        )])
+        .await;
+        let resp = source(&fake.url()).get().await.unwrap();
+        assert_eq!(resp.cooldown, 15);
+        assert_eq!(resp.servers.len(), 1);
+        assert_eq!(resp.servers[0].port, 7777);
+        assert_eq!(
+            resp.servers[0].players_list[0].nickname.as_deref(),
+            Some("alpha")
+        );
+        assert_eq!(
+            fake.requests.lock()[0],
+            "/serverinfo.php?id=7&key=secret&list=true&nicknames=true&online=true"
+        );
+    }
+
+    #[rocket::async_test]
+    async fn too_many_requests_is_a_rate_limit() {
+        let fake = FakeNorthwood::start(vec![
+            FakeResponse {
+                status: 429,
+                headers: vec![("Retry-After", "30".to_string())],
+                body: String::new(),
+            },
+            FakeResponse {
+                status: 429,
+                headers: vec![],
+                body: String::new(),
+            },
+        ])
+        .await;
+        let source = source(&fake.url());
+        assert_eq!(retry_after(source.get().await.unwrap_err()), Some(30));
+        assert_eq!(retry_after(source.get().await.unwrap_err()), None);
+    }
+
+    #[rocket::async_test]
+    async fn cooldown_in_the_body_is_a_rate_limit() {
+        let fake = FakeNorthwood::start(vec![
+            FakeResponse::json(r#"{"Success":false,"Error":"Rate limited","Cooldown":12}"#),
+            FakeResponse::json(r#"{"Success":false,"Error":"Access denied"}"#),
+        ])
+        .await;
+        let source = source(&fake.url());
+        assert_eq!(retry_after(source.get().await.unwrap_err()), Some(12));
+        let err = source.get().await.unwrap_err();
+        assert!(err.downcast_ref::<RateLimited>().is_none());
+        assert_eq!(err.to_string(), "API returned error: Access denied");
+    }
+
+    #[rocket::async_test]
+    async fn errors_dont_leak_the_key() {
+        // nothing listens on port 1, so the request itself fails
+        let err = source("http://127.0.0.1:1").get().await.unwrap_err();
+        assert!(!format!("{:?}", err).contains("secret"));
+    }
+}
@@ -0,0 +1,297 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv6Addr},
+    time::{Duration, Instant},
+};
+
+use lurky::config::{RateLimit, RateLimits};
+use parking_lot::{Mutex, RwLock};
+
+/// buckets that have filled back up are forgotten once there are this many
+const PRUNE_AT: usize = 10_000;
+/// and at most this often, so a flood of clients doesn't make every check walk all of them
+const PRUNE_EVERY: Duration = Duration::from_secs(10);
+/// past this, new clients share the Unknown bucket until some of the old ones are pruned
+const MAX_BUCKETS: usize = 100_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RouteGroup {
+    Public,
+    Query,
+    Nw,
+    Flags,
+    Admin,
+}
+
+impl RouteGroup {
+    fn limit(&self, limits: &RateLimits) -> RateLimit {
+        match self {
+            RouteGroup::Public => limits.public,
+            RouteGroup::Query => limits.query,
+            RouteGroup::Nw => limits.nw,
+            RouteGroup::Flags => limits.flags,
+            RouteGroup::Admin => limits.admin,
+        }
+    }
+}
+
+/// who a bucket belongs to, requests with a valid api key count against the key instead of the ip
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Client {
+    Key(u64),
+    Ip(IpAddr),
+    /// rocket couldn't tell where the request came from, they all share a bucket
+    Unknown,
+}
+
+impl Client {
+    /// ipv6 clients usually get a whole /64, so that is what gets a bucket
+    fn bucketed(&self) -> Client {
+        match self {
+            Client::Ip(IpAddr::V6(ip)) => {
+                let prefix = u128::from(*ip) & !(u64::MAX as u128);
+                Client::Ip(IpAddr::V6(Ipv6Addr::from(prefix)))
+            }
+            client => client.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn refilled(&self, limit: RateLimit, now: Instant) -> f64 {
+        let rate = limit.requests as f64 / limit.per as f64;
+        (self.tokens + now.duration_since(self.last).as_secs_f64() * rate)
+            .min(limit.requests as f64)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Buckets {
+    map: HashMap<(RouteGroup, Client), Bucket>,
+    pruned: Option<Instant>,
+}
+
+impl Buckets {
+    /// forgets the buckets that have filled back up, if it is time to
+    fn prune(&mut self, limits: &RateLimits, now: Instant) {
+        if self.map.len() < PRUNE_AT
+            || self
+                .pruned
+                .is_some_and(|at| now.saturating_duration_since(at) < PRUNE_EVERY)
+        {
+            return;
+        }
+        self.pruned = Some(now);
+        self.map.retain(|(group, _), bucket| {
+            let limit = group.limit(limits);
+            !limit.is_off() && bucket.refilled(limit, now) < limit.requests as f64
+        });
+    }
+    /// which bucket a client pays into, new clients go to Unknown once there are too many
+    fn slot(&self, group: RouteGroup, client: &Client) -> (RouteGroup, Client) {
+        let key = (group, client.bucketed());
+        if self.map.len() >= MAX_BUCKETS && !self.map.contains_key(&key) {
+            (group, Client::Unknown)
+        } else {
+            key
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// can change when the config is reloaded
+    limits: RwLock<RateLimits>,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            limits: RwLock::new(limits),
+            buckets: Mutex::new(Buckets::default()),
+        }
+    }
+    /// uses limits from now on. buckets keep what they have, but never more than the new capacity
+    pub fn reconfigure(&self, limits: RateLimits) {
+        *self.limits.write() = limits;
+    }
+    /// takes a token from the clients bucket, or says how long until there is one again
+    pub fn check(&self, group: RouteGroup, client: Client, now: Instant) -> Result<(), Duration> {
+        let limits = *self.limits.read();
+        let limit = group.limit(&limits);
+        if limit.is_off() {
+            return Ok(());
+        }
+        let mut buckets = self.buckets.lock();
+        buckets.prune(&limits, now);
+        let slot = buckets.slot(group, &client);
+        let bucket = buckets.map.entry(slot).or_insert(Bucket {
+            tokens: limit.requests as f64,
+            last: now,
+        });
+        bucket.tokens = bucket.refilled(limit, now);
+        bucket.last = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) * limit.per as f64 / limit.requests as f64,
+            ))
+        }
+    }
+    /// gives back the token a check took, for when someone else ends up paying for the request
+    pub fn refund(&self, group: RouteGroup, client: &Client) {
+        let limit = group.limit(&self.limits.read());
+        let mut buckets = self.buckets.lock();
+        let slot = buckets.slot(group, client);
+        if let Some(bucket) = buckets.map.get_mut(&slot) {
+            bucket.tokens = (bucket.tokens + 1.0).min(limit.requests as f64);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_refill() {
+        let limiter = RateLimiter::new(RateLimits {
+            public: RateLimit::new(2, 10),
+            admin: RateLimit::OFF,
+            ..Default::default()
+        });
+        let ip = Client::Ip("10.0.0.1".parse().unwrap());
+        let start = Instant::now();
+        assert!(limiter.check(RouteGroup::Public, ip.clone(), start).is_ok());
+        assert!(limiter.check(RouteGroup::Public, ip.clone(), start).is_ok());
+        let wait = limiter
+            .check(RouteGroup::Public, ip.clone(), start)
+            .unwrap_err();
+        assert_eq!(wait, Duration::from_secs(5));
+        // other clients and groups have their own buckets
+        assert!(limiter
+            .check(RouteGroup::Public, Client::Key(1), start)
+            .is_ok());
+        assert!(limiter.check(RouteGroup::Query, ip.clone(), start).is_ok());
+        for _ in 0..100 {
+            assert!(limiter.check(RouteGroup::Admin, ip.clone(), start).is_ok());
+        }
+
+        let later = start + Duration::from_secs(5);
+        assert!(limiter.check(RouteGroup::Public, ip.clone(), later).is_ok());
+        assert!(limiter
+            .check(RouteGroup::Public, ip.clone(), later)
+            .is_err());
+        // never more than the capacity saved up
+        let much_later = later + Duration::from_secs(3600);
+        assert!(limiter
+            .check(RouteGroup::Public, ip.clone(), much_later)
+            .is_ok());
+        assert!(limiter
+            .check(RouteGroup::Public, ip.clone(), much_later)
+            .is_ok());
+        assert!(limiter.check(RouteGroup::Public, ip, much_later).is_err());
+    }
+
+    #[test]
+    fn refunds_give_the_token_back() {
+        let limiter = RateLimiter::new(RateLimits {
+            public: RateLimit::new(1, 60),
+            ..Default::default()
+        });
+        let ip = Client::Ip("10.0.0.1".parse().unwrap());
+        let now = Instant::now();
+        assert!(limiter.check(RouteGroup::Public, ip.clone(), now).is_ok());
+        limiter.refund(RouteGroup::Public, &ip);
+        assert!(limiter.check(RouteGroup::Public, ip.clone(), now).is_ok());
+        assert!(limiter.check(RouteGroup::Public, ip.clone(), now).is_err());
+        // never past the capacity
+        limiter.refund(RouteGroup::Public, &ip);
+        limiter.refund(RouteGroup::Public, &ip);
+        assert!(limiter.check(RouteGroup::Public, ip.clone(), now).is_ok());
+        assert!(limiter.check(RouteGroup::Public, ip, now).is_err());
+    }
+
+    #[test]
+    fn reconfigured_limits_apply_to_old_buckets() {
+        let limiter = RateLimiter::new(RateLimits {
+            public: RateLimit::new(10, 10),
+            ..Default::default()
+        });
+        let ip = Client::Ip("10.0.0.1".parse().unwrap());
+        let now = Instant::now();
+        assert!(limiter.check(RouteGroup::Public, ip.clone(), now).is_ok());
+        limiter.reconfigure(RateLimits {
+            public: RateLimit::new(2, 10),
+            ..Default::default()
+        });
+        // it had 9 left, but can't keep more than the new capacity
+        assert!(limiter.check(RouteGroup::Public, ip.clone(), now).is_ok());
+        assert!(limiter.check(RouteGroup::Public, ip.clone(), now).is_ok());
+        assert!(limiter.check(RouteGroup::Public, ip.clone(), now).is_err());
+        limiter.reconfigure(RateLimits {
+            public: RateLimit::OFF,
+            ..Default::default()
+        });
+        assert!(limiter.check(RouteGroup::Public, ip, now).is_ok());
+    }
+
+    #[test]
+    fn ipv6_clients_share_a_64() {
+        let limiter = RateLimiter::new(RateLimits {
+            public: RateLimit::new(1, 60),
+            ..Default::default()
+        });
+        let now = Instant::now();
+        let ip = |ip: &str| Client::Ip(ip.parse().unwrap());
+        assert!(limiter
+            .check(RouteGroup::Public, ip("2001:db8::1"), now)
+            .is_ok());
+        assert!(limiter
+            .check(RouteGroup::Public, ip("2001:db8::ffff:1"), now)
+            .is_err());
+        assert!(limiter
+            .check(RouteGroup::Public, ip("2001:db8:0:1::1"), now)
+            .is_ok());
+    }
+
+    #[test]
+    fn a_flood_of_clients_is_bounded() {
+        let limiter = RateLimiter::new(RateLimits {
+            public: RateLimit::new(2, 60),
+            ..Default::default()
+        });
+        let now = Instant::now();
+        let ip = |i: u32| Client::Ip(IpAddr::V4(i.into()));
+        for i in 0..MAX_BUCKETS as u32 {
+            assert!(limiter.check(RouteGroup::Public, ip(i), now).is_ok());
+        }
+        assert_eq!(limiter.buckets.lock().map.len(), MAX_BUCKETS);
+        // none of them are full, so new ones share a bucket
+        let new = ip(MAX_BUCKETS as u32);
+        assert!(limiter.check(RouteGroup::Public, new.clone(), now).is_ok());
+        assert!(limiter.check(RouteGroup::Public, ip(u32::MAX), now).is_ok());
+        assert!(limiter.check(RouteGroup::Public, new.clone(), now).is_err());
+        assert_eq!(limiter.buckets.lock().map.len(), MAX_BUCKETS + 1);
+        // the old ones still have their own
+        assert!(limiter.check(RouteGroup::Public, ip(0), now).is_ok());
+        assert!(limiter.check(RouteGroup::Public, ip(0), now).is_err());
+
+        // once they fill back up they are pruned, but not more often than PRUNE_EVERY
+        let later = now + Duration::from_secs(60);
+        assert!(limiter
+            .check(RouteGroup::Public, new.clone(), later)
+            .is_ok());
+        assert!(limiter.buckets.lock().map.len() <= 2);
+        assert!(limiter.check(RouteGroup::Public, new, later).is_ok());
+    }
+}
@@ -0,0 +1,224 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use lurky::{config::LurkyConfig, logging::LogLevel};
+use rocket::tokio::{
+    self,
+    sync::{watch, Notify},
+};
+use tracing::{error, info, warn};
+
+use crate::ratelimit::RateLimiter;
+
+/// how often the config files are checked for changes
+const POLL: Duration = Duration::from_secs(2);
+
+/// the config everything runs with, it is swapped out whole when the file changes
+#[derive(Debug)]
+pub struct LiveConfig {
+    current: watch::Sender<Arc<LurkyConfig>>,
+}
+
+impl LiveConfig {
+    pub fn new(config: LurkyConfig) -> Self {
+        Self {
+            current: watch::channel(Arc::new(config)).0,
+        }
+    }
+    /// the config right now, keep it for a whole request or tick so it doesn't change halfway
+    pub fn get(&self) -> Arc<LurkyConfig> {
+        Arc::clone(&self.current.borrow())
+    }
+    /// changed() on it wakes up whenever a new config is swapped in
+    pub fn subscribe(&self) -> watch::Receiver<Arc<LurkyConfig>> {
+        self.current.subscribe()
+    }
+    /// swaps config in if it can be, the database can't be changed without a restart
+    pub fn replace(&self, config: LurkyConfig) -> Result<(), String> {
+        let current = self.get();
+        if config.db_type != current.db_type || config.db_url != current.db_url {
+            return Err("db_type and db_url can't change without a restart".to_string());
+        }
+        self.current.send_replace(Arc::new(config));
+        Ok(())
+    }
+}
+
+fn modified(files: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    files
+        .iter()
+        .map(|f| fs::metadata(f).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+/// reads the config again, gives back the files it was read from so they can be watched
+fn reload(live: &LiveConfig, path: &Path, overrides: &[String]) -> Result<Vec<PathBuf>, String> {
+    let raw = LurkyConfig::load_raw(path, overrides).map_err(|report| {
+        report.eprint();
+        report.to_string()
+    })?;
+    let files = raw.files().map(Path::to_path_buf).collect();
+    let config = LurkyConfig::from_raw(&raw).map_err(|report| {
+        report.eprint();
+        report.to_string()
+    })?;
+    live.replace(config)?;
+    Ok(files)
+}
+
+#[cfg(unix)]
+async fn forward_hangups(hup: Arc<Notify>) {
+    use rocket::tokio::signal::unix::{signal, SignalKind};
+    let mut signals = match signal(SignalKind::hangup()) {
+        Ok(signals) => signals,
+        Err(e) => {
+            warn!(error = %e, "Can't listen for SIGHUP, only file changes reload the config");
+            return;
+        }
+    };
+    while signals.recv().await.is_some() {
+        hup.notify_one();
+    }
+}
+
+/// reloads the config when one of its files changes or on SIGHUP, it shouldnt return
+pub async fn watch(live: Arc<LiveConfig>, path: PathBuf, overrides: Vec<String>) {
+    let hup = Arc::new(Notify::new());
+    #[cfg(unix)]
+    tokio::spawn(forward_hangups(Arc::clone(&hup)));
+    let mut files = match LurkyConfig::load_raw(&path, &overrides) {
+        Ok(raw) => raw.files().map(Path::to_path_buf).collect(),
+        Err(_) => vec![path.clone()],
+    };
+    let mut seen = modified(&files);
+    let mut poll = tokio::time::interval(POLL);
+    poll.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = hup.notified() => info!("Got SIGHUP, reloading the config"),
+            _ = poll.tick() => {
+                if modified(&files) == seen {
+                    continue;
+                }
+                info!("The config changed, reloading it");
+            }
+        }
+        match reload(&live, &path, &overrides) {
+            Ok(new_files) => {
+                files = new_files;
+                info!("Reloaded the config");
+            }
+            Err(e) => error!(error = %e, "Not reloading the config"),
+        }
+        seen = modified(&files);
+    }
+}
+
+/// applies what the routes don't read from LiveConfig themselves: rate_limits and log_level.
+/// log_format is set up once, changing it needs a restart. it shouldnt return
+pub async fn follow(live: Arc<LiveConfig>, limiter: Arc<RateLimiter>, log_level: LogLevel) {
+    let mut changes = live.subscribe();
+    let startup = live.get();
+    let mut applied = Arc::clone(&startup);
+    while changes.changed().await.is_ok() {
+        let new = live.get();
+        apply(&applied, &new, &startup, &limiter, &log_level);
+        applied = new;
+    }
+}
+
+fn apply(
+    old: &LurkyConfig,
+    new: &LurkyConfig,
+    startup: &LurkyConfig,
+    limiter: &RateLimiter,
+    log_level: &LogLevel,
+) {
+    if new.rate_limits != old.rate_limits {
+        limiter.reconfigure(new.rate_limits);
+        info!("Applied the new rate_limits");
+    }
+    if new.log_level != old.log_level {
+        match log_level.set(&new.log_level) {
+            Ok(()) => info!(log_level = %new.log_level, "Applied the new log_level"),
+            Err(e) => error!(error = %e, "Not applying the new log_level"),
+        }
+    }
+    if new.log_format != old.log_format && new.log_format != startup.log_format {
+        warn!("log_format can't change without a restart, still using the old one");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(text: &str) -> LurkyConfig {
+        LurkyConfig::parse_data(text.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn only_safe_changes_are_swapped_in() {
+        let base = "db_type:memory\ndb_url:\nrefresh_cooldown:30s\nauth_key:a\n";
+        let live = LiveConfig::new(config(&format!("{}servers:1|k\n", base)));
+        let before = live.get();
+
+        live.replace(config(&format!("{}servers:1|k,2|j\n", base)))
+            .unwrap();
+        assert_eq!(live.get().servers.len(), 2);
+        // whoever had the old one keeps it
+        assert_eq!(before.servers.len(), 1);
+
+        assert!(live
+            .replace(config(&format!(
+                "{}servers:1|k\n",
+                base.replace(
+                    "db_type:memory\ndb_url:",
+                    "db_type:sqlite\ndb_url:sqlite://other.db"
+                )
+            )))
+            .is_err());
+        assert_eq!(live.get().servers.len(), 2);
+    }
+
+    #[test]
+    fn rate_limits_and_log_level_are_applied() {
+        use lurky::config::{RateLimit, RateLimits};
+        use parking_lot::Mutex;
+        use std::time::Instant;
+
+        use crate::ratelimit::{Client, RouteGroup};
+
+        let old =
+            config("db_type:memory\ndb_url:\nrefresh_cooldown:30s\nauth_key:a\nservers:1|k\n");
+        let mut new = config("db_type:memory\ndb_url:\nrefresh_cooldown:30s\nauth_key:a\nservers:1|k\nlog_level:debug\n");
+        new.rate_limits = RateLimits {
+            public: RateLimit::new(1, 60),
+            ..Default::default()
+        };
+        let limiter = RateLimiter::new(old.rate_limits);
+        let levels = Arc::new(Mutex::new(Vec::new()));
+        let log_level = LogLevel::new({
+            let levels = Arc::clone(&levels);
+            move |level| {
+                levels.lock().push(level.to_string());
+                Ok(())
+            }
+        });
+
+        apply(&old, &new, &old, &limiter, &log_level);
+        assert_eq!(*levels.lock(), vec!["debug".to_string()]);
+        let ip = Client::Ip("10.0.0.1".parse().unwrap());
+        let now = Instant::now();
+        assert!(limiter.check(RouteGroup::Public, ip.clone(), now).is_ok());
+        assert!(limiter.check(RouteGroup::Public, ip, now).is_err());
+
+        // nothing changed, nothing is set again
+        apply(&new, &new, &old, &limiter, &log_level);
+        assert_eq!(levels.lock().len(), 1);
+    }
+}
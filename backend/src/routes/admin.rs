@@ -0,0 +1,108 @@
+use std::{net::IpAddr, sync::Arc};
+
+use crate::{
+    audit::{AuthAudit, AuthFailure, Lockout},
+    db::{LurkyError, ManagedDB},
+};
+use lurky::{
+    auth::{generate_key, hash_key, ApiKey, Scope},
+    query::{DEFAULT_LIMIT, MAX_LIMIT},
+};
+use rocket::{delete, get, post, routes, serde::json::Json, Route, State};
+use serde::{Deserialize, Serialize};
+
+use super::query::{ApiError, DBResult};
+use super::{Admin, AdminRoutes, RateLimited, RequireScope};
+
+#[derive(Deserialize)]
+pub struct NewKey {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+}
+
+/// the only time the key itself is sent, only its hash is stored
+#[derive(Serialize)]
+pub struct CreatedKey {
+    pub key: String,
+    #[serde(flatten)]
+    pub info: ApiKey,
+}
+
+#[get("/keys")]
+pub async fn list_keys(
+    _rate: RateLimited<AdminRoutes>,
+    _auth: RequireScope<Admin>,
+    db: &State<Arc<ManagedDB>>,
+) -> DBResult<Vec<ApiKey>> {
+    Ok(Json(db.list_api_keys().await?))
+}
+
+#[post("/keys", data = "<key>")]
+pub async fn create_key(
+    key: Json<NewKey>,
+    _rate: RateLimited<AdminRoutes>,
+    _auth: RequireScope<Admin>,
+    db: &State<Arc<ManagedDB>>,
+) -> DBResult<CreatedKey> {
+    let new = key.into_inner();
+    if new.name.is_empty() {
+        return Err(ApiError(LurkyError::InvalidQuery(
+            "Key name can't be empty".to_string(),
+        )));
+    }
+    let key = generate_key();
+    let info = db
+        .create_api_key(&new.name, &new.scopes, &hash_key(&key))
+        .await?;
+    Ok(Json(CreatedKey { key, info }))
+}
+
+#[delete("/keys/<id>")]
+pub async fn delete_key(
+    id: u64,
+    _rate: RateLimited<AdminRoutes>,
+    _auth: RequireScope<Admin>,
+    db: &State<Arc<ManagedDB>>,
+) -> DBResult<Vec<ApiKey>> {
+    db.delete_api_key(id).await?;
+    Ok(Json(db.list_api_keys().await?))
+}
+
+/// failed authentications, newest first, optionally only the ones from ip
+#[get("/audit?<ip>&<limit>")]
+pub fn audit_log(
+    ip: Option<IpAddr>,
+    limit: Option<u64>,
+    _rate: RateLimited<AdminRoutes>,
+    _auth: RequireScope<Admin>,
+    audit: &State<Arc<AuthAudit>>,
+) -> Json<Vec<AuthFailure>> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+    Json(audit.failures(ip, limit as usize))
+}
+
+#[get("/lockouts")]
+pub fn lockouts(
+    _rate: RateLimited<AdminRoutes>,
+    _auth: RequireScope<Admin>,
+    audit: &State<Arc<AuthAudit>>,
+) -> Json<Vec<Lockout>> {
+    Json(audit.lockouts(time::OffsetDateTime::now_utc()))
+}
+
+#[delete("/lockouts/<ip>")]
+pub fn unlock(
+    ip: IpAddr,
+    _rate: RateLimited<AdminRoutes>,
+    _auth: RequireScope<Admin>,
+    audit: &State<Arc<AuthAudit>>,
+) -> DBResult<Vec<Lockout>> {
+    if !audit.unlock(ip) {
+        return Err(ApiError(LurkyError::NotFound(format!("Lockout of {}", ip))));
+    }
+    Ok(Json(audit.lockouts(time::OffsetDateTime::now_utc())))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![list_keys, create_key, delete_key, audit_log, lockouts, unlock]
+}
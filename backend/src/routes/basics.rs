@@ -4,9 +4,9 @@ use rocket::{
     get, http::Status, response::status::Custom, routes, tokio::task::JoinHandle, Route, State,
 };
 
-use crate::db::ManagedDB;
+use crate::{db::ManagedDB, state::ServerState};
 
-use super::Authenticated;
+use super::{ReadPublic, RequireScope};
 
 #[get("/")]
 pub fn index() -> &'static str {
@@ -14,20 +14,44 @@ pub fn index() -> &'static str {
 }
 
 #[get("/test")]
-pub fn test_auth(_auth: Authenticated) -> &'static str {
-    "Auth OK!"
+pub fn test_auth(auth: RequireScope<ReadPublic>) -> String {
+    format!("Auth OK! Using key {}", auth.name)
 }
 
 #[get("/health")]
-pub async fn health(g: &State<JoinHandle<()>>, db: &State<Arc<ManagedDB>>) -> Custom<&'static str> {
+pub async fn health(
+    g: &State<JoinHandle<()>>,
+    db: &State<Arc<ManagedDB>>,
+    state: &State<Arc<ServerState>>,
+) -> Custom<String> {
     if g.is_finished() {
-        Custom(Status::InternalServerError, "Backend thread died!")
+        return Custom(
+            Status::InternalServerError,
+            "Backend thread died!".to_string(),
+        );
+    }
+    if db.health().await.is_err() {
+        return Custom(Status::InternalServerError, "DB is dead!".to_string());
+    }
+    let degraded = state
+        .all()
+        .into_iter()
+        .filter(|s| s.degraded)
+        .map(|s| match (s.stale, s.error) {
+            (true, Some(e)) => format!("{} (stale, {})", s.id, e),
+            (true, None) => format!("{} (stale)", s.id),
+            (false, Some(e)) => format!("{} ({} failures, {})", s.id, s.failures, e),
+            (false, None) => s.id.to_string(),
+        })
+        .collect::<Vec<String>>();
+    if degraded.is_empty() {
+        Custom(Status::Ok, "OK".to_string())
     } else {
-        if db.health().await.is_err() {
-            Custom(Status::InternalServerError, "DB is dead!")
-        } else {
-            Custom(Status::Ok, "OK")
-        }
+        // still up, we just can't see some of the servers properly
+        Custom(
+            Status::Ok,
+            format!("DEGRADED, servers: {}", degraded.join("; ")),
+        )
     }
 }
 
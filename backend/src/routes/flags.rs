@@ -0,0 +1,177 @@
+use std::sync::Arc;
+
+use crate::db::{Flag, ManagedDB};
+use rocket::{delete, get, post, routes, serde::json::Json, Route, State};
+use serde::Deserialize;
+
+use super::query::DBResult;
+use super::{FlagRoutes, RateLimited, ReadPlayers, RequireScope, WriteFlags};
+
+#[derive(Deserialize)]
+pub struct NewFlag {
+    pub flag: i64,
+    #[serde(default)]
+    pub comment: String,
+}
+
+#[get("/<id>")]
+pub async fn list_flags(
+    id: u64,
+    _rate: RateLimited<FlagRoutes>,
+    _auth: RequireScope<ReadPlayers>,
+    db: &State<Arc<ManagedDB>>,
+) -> DBResult<Vec<Flag>> {
+    Ok(Json(db.get_flags(id).await?))
+}
+
+#[post("/<id>", data = "<flag>")]
+pub async fn add_flag(
+    id: u64,
+    flag: Json<NewFlag>,
+    _rate: RateLimited<FlagRoutes>,
+    auth: RequireScope<WriteFlags>,
+    db: &State<Arc<ManagedDB>>,
+) -> DBResult<Vec<Flag>> {
+    let flag = flag.into_inner();
+    db.add_flag(
+        id,
+        Flag {
+            flag: flag.flag,
+            issuer: auth.name,
+            issued_at: time::OffsetDateTime::now_utc(),
+            comment: flag.comment,
+            revoked_at: None,
+            revoked_by: None,
+        },
+    )
+    .await?;
+    Ok(Json(db.get_flags(id).await?))
+}
+
+#[delete("/<id>/<flag>")]
+pub async fn revoke_flag(
+    id: u64,
+    flag: i64,
+    _rate: RateLimited<FlagRoutes>,
+    auth: RequireScope<WriteFlags>,
+    db: &State<Arc<ManagedDB>>,
+) -> DBResult<Vec<Flag>> {
+    db.revoke_flag(id, flag, &auth.name, time::OffsetDateTime::now_utc())
+        .await?;
+    Ok(Json(db.get_flags(id).await?))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![list_flags, add_flag, revoke_flag]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{audit::AuthAudit, ratelimit::RateLimiter, reload::LiveConfig};
+    use lurky::{
+        config::{LogFormat, LurkyConfig, RateLimits},
+        db::DBPlayer,
+    };
+    use rocket::{
+        http::{Header, Status},
+        local::asynchronous::Client,
+    };
+
+    async fn client() -> Client {
+        let config = LurkyConfig {
+            servers: vec![],
+            auth_key: "test".to_string(),
+            db_type: "memory".to_string(),
+            db_url: String::new(),
+            refresh_cooldown: std::time::Duration::from_secs(60),
+            nw_api_url: String::new(),
+            rate_limits: RateLimits::default(),
+            http_cat_dir: String::new(),
+            log_level: String::new(),
+            log_format: LogFormat::Text,
+            trusted_proxies: vec![],
+        };
+        let db = crate::db::create_db_from_config(&config).unwrap();
+        let now = time::OffsetDateTime::now_utc();
+        db.create_player(DBPlayer {
+            id: 1,
+            first_seen: now,
+            last_seen: now,
+            play_time: time::Duration::ZERO,
+            last_nickname: "alpha".to_string(),
+            nicknames: vec!["alpha".to_string()],
+            flags: vec![],
+            time_online: time::Duration::ZERO,
+            login_amt: 1,
+        })
+        .await
+        .unwrap();
+        let rocket = rocket::build()
+            .mount("/flags", routes())
+            .manage(Arc::new(LiveConfig::new(config)))
+            .manage(Arc::new(db))
+            .manage(Arc::new(RateLimiter::new(RateLimits::default())))
+            .manage(Arc::new(AuthAudit::new()));
+        Client::tracked(rocket).await.unwrap()
+    }
+
+    fn auth() -> Header<'static> {
+        Header::new("Authorization", "Bearer test")
+    }
+
+    async fn flags(client: &Client) -> Vec<Flag> {
+        let res = client.get("/flags/1").header(auth()).dispatch().await;
+        assert_eq!(res.status(), Status::Ok);
+        res.into_json().await.unwrap()
+    }
+
+    #[rocket::async_test]
+    async fn issue_list_and_revoke() {
+        let client = client().await;
+        assert!(flags(&client).await.is_empty());
+
+        // the issuer comes from the key, not from the body
+        let res = client
+            .post("/flags/1")
+            .header(auth())
+            .body(r#"{"flag": 5, "issuer": "someone else", "comment": "reason"}"#)
+            .dispatch()
+            .await;
+        assert_eq!(res.status(), Status::Ok);
+        let issued: Vec<Flag> = res.into_json().await.unwrap();
+        assert_eq!(issued.len(), 1);
+        assert_eq!(issued[0].flag, 5);
+        assert_eq!(issued[0].issuer, "config");
+        assert_eq!(issued[0].comment, "reason");
+        assert!(issued[0].is_active());
+
+        let res = client.delete("/flags/1/5").header(auth()).dispatch().await;
+        assert_eq!(res.status(), Status::Ok);
+        // the revoked flag is still listed
+        let listed = flags(&client).await;
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].revoked_by.as_deref(), Some("config"));
+        assert!(listed[0].revoked_at.is_some());
+
+        let res = client.delete("/flags/1/5").header(auth()).dispatch().await;
+        assert_eq!(res.status(), Status::NotFound);
+        let res = client.delete("/flags/2/5").header(auth()).dispatch().await;
+        assert_eq!(res.status(), Status::NotFound);
+    }
+
+    #[rocket::async_test]
+    async fn needs_a_key() {
+        let client = client().await;
+        let res = client.get("/flags/1").dispatch().await;
+        assert_eq!(res.status(), Status::Unauthorized);
+        let res = client
+            .post("/flags/1")
+            .body(r#"{"flag": 5}"#)
+            .dispatch()
+            .await;
+        assert_eq!(res.status(), Status::Unauthorized);
+        let res = client.delete("/flags/1/5").dispatch().await;
+        assert_eq!(res.status(), Status::Unauthorized);
+    }
+}
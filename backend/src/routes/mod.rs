@@ -1,43 +1,416 @@
+use rocket::http::Status;
 use rocket::request::{self, FromRequest, Outcome, Request};
 //use rocket::State;
-use std::sync::Arc;
+use std::{
+    marker::PhantomData,
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use subtle::ConstantTimeEq;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
 
-use lurky::config::LurkyConfig;
+use crate::{
+    audit::{AuthAudit, AuthFailure},
+    catcher::{ErrorReason, RetryAfter},
+    ratelimit::{Client, RateLimiter, RouteGroup},
+    reload::LiveConfig,
+};
 
+use lurky::{
+    auth::{hash_key, is_well_formed, ApiKey, Scope},
+    db::{LurkyError, ManagedDB},
+};
+
+pub mod admin;
 pub mod basics;
+pub mod flags;
 pub mod northwood;
 pub mod query;
 //pub type ConfigArgument = State<Arc<Config>>;
 
-pub struct Authenticated;
+/// a scope a route needs, see RequireScope
+pub trait ScopeMarker: Send + Sync + 'static {
+    const SCOPE: Scope;
+}
+
+pub struct ReadPublic;
+impl ScopeMarker for ReadPublic {
+    const SCOPE: Scope = Scope::ReadPublic;
+}
+
+pub struct ReadPlayers;
+impl ScopeMarker for ReadPlayers {
+    const SCOPE: Scope = Scope::ReadPlayers;
+}
+
+pub struct WriteFlags;
+impl ScopeMarker for WriteFlags {
+    const SCOPE: Scope = Scope::WriteFlags;
+}
+
+pub struct Admin;
+impl ScopeMarker for Admin {
+    const SCOPE: Scope = Scope::Admin;
+}
+
+/// succeeds if the request has an `Authorization: Bearer <key>` header with a key that has scope S.
+/// auth_key from the config is an admin key, so there is always a way to make the first real keys
+pub struct RequireScope<S: ScopeMarker> {
+    /// name of the key that was used
+    pub name: String,
+    _scope: PhantomData<S>,
+}
+
+/// the address the request came from, what lockouts, the audit log and rate limits are keyed on.
+/// X-Real-IP is only believed from one of trusted_proxies, anyone else could make it up
+pub fn remote_ip(req: &Request<'_>) -> Option<IpAddr> {
+    let remote = req.remote()?.ip();
+    let trusted = req
+        .rocket()
+        .state::<Arc<LiveConfig>>()
+        .is_some_and(|conf| conf.get().trusted_proxies.contains(&remote));
+    match trusted {
+        true => req.real_ip().or(Some(remote)),
+        false => Some(remote),
+    }
+}
+
+/// records the failure in the audit log and fails the guard with it
+fn fail<T>(
+    req: &Request<'_>,
+    audit: &AuthAudit,
+    status: Status,
+    reason: String,
+) -> request::Outcome<T, anyhow::Error> {
+    audit.record(AuthFailure {
+        at: OffsetDateTime::now_utc(),
+        ip: remote_ip(req),
+        method: req.method().as_str().to_string(),
+        route: req.uri().path().to_string(),
+        status: status.code,
+        reason: reason.clone(),
+    });
+    req.local_cache(|| ErrorReason(Some(reason.clone())));
+    Outcome::Error((status, anyhow::anyhow!(reason)))
+}
+
 #[rocket::async_trait]
-impl<'r> FromRequest<'r> for Authenticated {
+impl<'r, S: ScopeMarker> FromRequest<'r> for RequireScope<S> {
     type Error = anyhow::Error;
     async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let audit = req
+            .rocket()
+            .state::<Arc<AuthAudit>>()
+            .expect("The audit log to be present??");
+        // not having a key at all isn't an attempt, so it isn't audited
+        if req.headers().get_one("Authorization").is_none() {
+            req.local_cache(|| ErrorReason(Some("No key provided!".to_string())));
+            return Outcome::Error((Status::Unauthorized, anyhow::anyhow!("No key provided!")));
+        }
+        let ip = remote_ip(req);
+        if let Some(until) = ip.and_then(|ip| audit.locked_until(ip, OffsetDateTime::now_utc())) {
+            let wait = until - OffsetDateTime::now_utc();
+            req.local_cache(|| RetryAfter(wait.try_into().ok()));
+            return fail(
+                req,
+                audit,
+                Status::TooManyRequests,
+                format!(
+                    "Too many failed attempts, locked out until {}",
+                    until.format(&Rfc3339).unwrap_or_default()
+                ),
+            );
+        }
+        let key = match request_key(req).await {
+            RequestKey::Key(key) => key,
+            RequestKey::None => {
+                return fail(req, audit, Status::Unauthorized, "Invalid key!".to_string())
+            }
+            // not the clients fault, so it isn't audited
+            RequestKey::Unavailable => {
+                let reason = "Keys can't be checked right now".to_string();
+                req.local_cache(|| ErrorReason(Some(reason.clone())));
+                return Outcome::Error((Status::ServiceUnavailable, anyhow::anyhow!(reason)));
+            }
+        };
+        audit.record_success(ip);
+        if !key.allows(S::SCOPE) {
+            return fail(
+                req,
+                audit,
+                Status::Forbidden,
+                format!("Key {} is missing the {} scope!", key.name, S::SCOPE),
+            );
+        }
+        Outcome::Success(RequireScope {
+            name: key.name.clone(),
+            _scope: PhantomData,
+        })
+    }
+}
+
+/// the key the request was made with, looked up once per request and shared by the guards
+enum RequestKey {
+    Key(ApiKey),
+    /// there is no bearer key or it isn't one we know
+    None,
+    /// the database couldn't be asked
+    Unavailable,
+}
+
+async fn request_key<'r>(req: &'r Request<'_>) -> &'r RequestKey {
+    req.local_cache_async(async {
         let conf = req
             .rocket()
-            .state::<Arc<LurkyConfig>>()
-            .expect("The config to be present??");
-        let key = req.headers().get_one("Authorization");
-        let valid_key: Result<String, _> = Ok(conf.auth_key.clone()); // TODO: fix pls
-        match valid_key {
-            Ok(valid_key) => match key {
-                Some(passed_key) => {
-                    if format!("Bearer {}", valid_key) == passed_key {
-                        Outcome::Success(Authenticated)
-                    } else {
-                        Outcome::Failure((
-                            rocket::http::Status::Unauthorized,
-                            anyhow::anyhow!("Invalid key!"),
-                        ))
-                    }
-                }
-                None => Outcome::Failure((
-                    rocket::http::Status::Unauthorized,
-                    anyhow::anyhow!("No key provided!"),
-                )),
+            .state::<Arc<LiveConfig>>()
+            .expect("The config to be present??")
+            .get();
+        let db = req
+            .rocket()
+            .state::<Arc<ManagedDB>>()
+            .expect("The database to be present??");
+        let Some(passed_key) = req
+            .headers()
+            .get_one("Authorization")
+            .and_then(|h| h.strip_prefix("Bearer "))
+        else {
+            return RequestKey::None;
+        };
+        // the hashes are always the same length, so comparing them doesn't leak how long auth_key is
+        let hash = hash_key(passed_key);
+        if !conf.auth_key.is_empty()
+            && bool::from(hash.as_bytes().ct_eq(hash_key(&conf.auth_key).as_bytes()))
+        {
+            return RequestKey::Key(ApiKey {
+                id: 0,
+                name: "config".to_string(),
+                scopes: vec![Scope::Admin],
+                created: OffsetDateTime::UNIX_EPOCH,
+            });
+        }
+        // only generated keys are in the database, so made up ones don't cost a query
+        if !is_well_formed(passed_key) {
+            return RequestKey::None;
+        }
+        match db.get_api_key(&hash).await {
+            Ok(key) => RequestKey::Key(key),
+            Err(LurkyError::NotFound(_)) => RequestKey::None,
+            Err(e) => {
+                tracing::error!(error = %e, "Failed to look up key");
+                RequestKey::Unavailable
+            }
+        }
+    })
+    .await
+}
+
+/// a group of routes that share a rate limit, see RateLimited
+pub trait GroupMarker: Send + Sync + 'static {
+    const GROUP: RouteGroup;
+}
+
+pub struct PublicRoutes;
+impl GroupMarker for PublicRoutes {
+    const GROUP: RouteGroup = RouteGroup::Public;
+}
+
+pub struct QueryRoutes;
+impl GroupMarker for QueryRoutes {
+    const GROUP: RouteGroup = RouteGroup::Query;
+}
+
+pub struct NwRoutes;
+impl GroupMarker for NwRoutes {
+    const GROUP: RouteGroup = RouteGroup::Nw;
+}
+
+pub struct FlagRoutes;
+impl GroupMarker for FlagRoutes {
+    const GROUP: RouteGroup = RouteGroup::Flags;
+}
+
+pub struct AdminRoutes;
+impl GroupMarker for AdminRoutes {
+    const GROUP: RouteGroup = RouteGroup::Admin;
+}
+
+/// takes a token from the bucket of group G, requests with a valid key use the keys bucket and
+/// everything else the bucket of its ip. fails with a 429 once it is empty
+pub struct RateLimited<G: GroupMarker>(PhantomData<G>);
+
+/// fails the guard with a 429 that says when to come back
+fn rate_limited<T>(req: &Request<'_>, wait: Duration) -> request::Outcome<T, anyhow::Error> {
+    let reason = format!("Rate limited, try again in {:.1}s", wait.as_secs_f64());
+    req.local_cache(|| RetryAfter(Some(wait)));
+    req.local_cache(|| ErrorReason(Some(reason.clone())));
+    Outcome::Error((Status::TooManyRequests, anyhow::anyhow!(reason)))
+}
+
+#[rocket::async_trait]
+impl<'r, G: GroupMarker> FromRequest<'r> for RateLimited<G> {
+    type Error = anyhow::Error;
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let limiter = req
+            .rocket()
+            .state::<Arc<RateLimiter>>()
+            .expect("The rate limiter to be present??");
+        let now = Instant::now();
+        let ip = match remote_ip(req) {
+            Some(ip) => Client::Ip(ip),
+            None => Client::Unknown,
+        };
+        // the ip pays before the key is looked up, so a flood of made up keys is stopped here
+        if let Err(wait) = limiter.check(G::GROUP, ip.clone(), now) {
+            return rate_limited(req, wait);
+        }
+        if let RequestKey::Key(key) = request_key(req).await {
+            limiter.refund(G::GROUP, &ip);
+            if let Err(wait) = limiter.check(G::GROUP, Client::Key(key.id), now) {
+                return rate_limited(req, wait);
+            }
+        }
+        Outcome::Success(RateLimited(PhantomData))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::MAX_FAILURES;
+    use lurky::config::{LogFormat, LurkyConfig, RateLimit, RateLimits};
+    use rocket::{http::Header, local::asynchronous::Client, routes};
+    use std::net::SocketAddr;
+
+    #[rocket::get("/limited")]
+    fn limited(_rate: RateLimited<AdminRoutes>) -> &'static str {
+        "ok"
+    }
+
+    async fn client(trusted_proxies: Vec<IpAddr>) -> Client {
+        client_with(trusted_proxies, RateLimits::default()).await
+    }
+
+    async fn client_with(trusted_proxies: Vec<IpAddr>, rate_limits: RateLimits) -> Client {
+        let config = LurkyConfig {
+            servers: vec![],
+            auth_key: "test".to_string(),
+            db_type: "memory".to_string(),
+            db_url: String::new(),
+            refresh_cooldown: std::time::Duration::from_secs(60),
+            nw_api_url: String::new(),
+            rate_limits: RateLimits::default(),
+            http_cat_dir: String::new(),
+            log_level: String::new(),
+            log_format: LogFormat::Text,
+            trusted_proxies,
+        };
+        let db = crate::db::create_db_from_config(&config).unwrap();
+        let rocket = rocket::build()
+            .mount("/", routes![basics::test_auth, limited])
+            .manage(Arc::new(LiveConfig::new(config)))
+            .manage(Arc::new(db))
+            .manage(Arc::new(RateLimiter::new(rate_limits)))
+            .manage(Arc::new(AuthAudit::new()));
+        Client::tracked(rocket).await.unwrap()
+    }
+
+    /// GET /test from remote, claiming to be real_ip
+    async fn attempt(client: &Client, remote: &str, real_ip: &str, key: &str) -> Status {
+        get(client, "/test", remote, real_ip, key).await
+    }
+
+    async fn get(client: &Client, route: &str, remote: &str, real_ip: &str, key: &str) -> Status {
+        client
+            .get(route.to_string())
+            .remote(SocketAddr::new(remote.parse().unwrap(), 4000))
+            .header(Header::new("X-Real-IP", real_ip.to_string()))
+            .header(Header::new("Authorization", format!("Bearer {}", key)))
+            .dispatch()
+            .await
+            .status()
+    }
+
+    #[rocket::async_test]
+    async fn spoofed_real_ip_is_ignored() {
+        let client = client(vec![]).await;
+        // a different made up address every time doesn't spread the failures out
+        for i in 0..MAX_FAILURES {
+            let spoofed = format!("192.0.2.{}", i);
+            assert_eq!(
+                attempt(&client, "10.0.0.1", &spoofed, "wrong").await,
+                Status::Unauthorized
+            );
+        }
+        assert_eq!(
+            attempt(&client, "10.0.0.1", "192.0.2.200", "test").await,
+            Status::TooManyRequests
+        );
+        // and claiming to be someone else didn't lock them out
+        for _ in 0..MAX_FAILURES {
+            attempt(&client, "10.0.0.3", "10.0.0.2", "wrong").await;
+        }
+        assert_eq!(
+            attempt(&client, "10.0.0.2", "10.0.0.2", "test").await,
+            Status::Ok
+        );
+    }
+
+    #[rocket::async_test]
+    async fn trusted_proxies_pass_the_real_ip_on() {
+        let client = client(vec!["127.0.0.1".parse().unwrap()]).await;
+        for _ in 0..MAX_FAILURES {
+            attempt(&client, "127.0.0.1", "192.0.2.1", "wrong").await;
+        }
+        assert_eq!(
+            attempt(&client, "127.0.0.1", "192.0.2.1", "test").await,
+            Status::TooManyRequests
+        );
+        // the proxy itself and everyone else behind it are fine
+        assert_eq!(
+            attempt(&client, "127.0.0.1", "192.0.2.2", "test").await,
+            Status::Ok
+        );
+    }
+
+    #[rocket::async_test]
+    async fn the_ip_pays_before_the_key_is_checked() {
+        let client = client_with(
+            vec![],
+            RateLimits {
+                admin: RateLimit::new(2, 60),
+                ..Default::default()
             },
-            Err(e) => return Outcome::Failure((rocket::http::Status::InternalServerError, e)),
+        )
+        .await;
+        let made_up = format!("lk_{}", "0".repeat(64));
+        for i in 0..2 {
+            let spoofed = format!("192.0.2.{}", i);
+            assert_eq!(
+                get(&client, "/limited", "10.0.0.1", &spoofed, &made_up).await,
+                Status::Ok
+            );
+        }
+        // neither a made up key nor a made up address gets a new bucket
+        assert_eq!(
+            get(&client, "/limited", "10.0.0.1", "192.0.2.9", &made_up).await,
+            Status::TooManyRequests
+        );
+
+        // a valid key pays for itself, and leaves the ips bucket alone
+        for _ in 0..2 {
+            assert_eq!(
+                get(&client, "/limited", "10.0.0.2", "10.0.0.2", "test").await,
+                Status::Ok
+            );
         }
+        assert_eq!(
+            get(&client, "/limited", "10.0.0.3", "10.0.0.3", "test").await,
+            Status::TooManyRequests
+        );
+        assert_eq!(
+            get(&client, "/limited", "10.0.0.2", "10.0.0.2", "nope").await,
+            Status::Ok
+        );
     }
 }
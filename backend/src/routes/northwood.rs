@@ -1,40 +1,70 @@
-use rocket::{get, response::status::NotFound, routes, Route};
+use std::sync::Arc;
 
-use super::Authenticated;
+use rocket::{get, response::status::NotFound, routes, Route, State};
+
+use super::{NwRoutes, RateLimited, ReadPlayers, ReadPublic, RequireScope};
 use crate::{
-    backend::CACHED_NW_REQ,
     northwood::{SLResponse, SLServer},
+    state::{ServerState, ServerStatus},
 };
 use rocket::serde::json::Json;
 
+/// id is the server id from the config
 #[get("/<id>")]
-pub fn nw_api(id: usize, _auth: Authenticated) -> Result<Json<SLResponse>, NotFound<String>> {
-    let nw_ca = CACHED_NW_REQ.read();
-    nw_ca
+pub fn nw_api(
+    id: u64,
+    _rate: RateLimited<NwRoutes>,
+    _auth: RequireScope<ReadPlayers>,
+    state: &State<Arc<ServerState>>,
+) -> Result<Json<SLResponse>, NotFound<String>> {
+    state
+        .get(id)
+        .and_then(|e| e.response)
+        .map(Json)
+        .ok_or(NotFound(format!("No response for server with id {}", id)))
+}
+
+#[get("/<id>/status")]
+pub fn nw_api_status(
+    id: u64,
+    _rate: RateLimited<NwRoutes>,
+    _auth: RequireScope<ReadPublic>,
+    state: &State<Arc<ServerState>>,
+) -> Result<Json<ServerStatus>, NotFound<String>> {
+    state
         .get(id)
-        .and_then(|e| e.clone())
-        .and_then(|e| Some(Json(e.clone())))
+        .map(Json)
         .ok_or(NotFound(format!("Server with id {} not found", id)))
 }
 
+#[get("/status")]
+pub fn nw_api_statuses(
+    _rate: RateLimited<NwRoutes>,
+    _auth: RequireScope<ReadPublic>,
+    state: &State<Arc<ServerState>>,
+) -> Json<Vec<ServerStatus>> {
+    Json(state.all())
+}
+
 #[get("/all")]
-pub fn nw_api_all(_auth: Authenticated) -> Json<Vec<SLResponse>> {
-    Json(
-        CACHED_NW_REQ
-            .read()
-            .iter()
-            .filter_map(|e| e.clone())
-            .collect::<Vec<SLResponse>>(),
-    )
+pub fn nw_api_all(
+    _rate: RateLimited<NwRoutes>,
+    _auth: RequireScope<ReadPlayers>,
+    state: &State<Arc<ServerState>>,
+) -> Json<Vec<SLResponse>> {
+    Json(state.responses())
 }
 
 #[get("/servers")]
-pub fn nw_api_servers(_auth: Authenticated) -> Json<Vec<SLServer>> {
+pub fn nw_api_servers(
+    _rate: RateLimited<NwRoutes>,
+    _auth: RequireScope<ReadPlayers>,
+    state: &State<Arc<ServerState>>,
+) -> Json<Vec<SLServer>> {
     Json(
-        CACHED_NW_REQ
-            .read()
-            .iter()
-            .filter_map(|e| e.clone())
+        state
+            .responses()
+            .into_iter()
             .flat_map(|e| e.servers)
             .collect::<Vec<SLServer>>(),
     )
@@ -42,9 +72,16 @@ pub fn nw_api_servers(_auth: Authenticated) -> Json<Vec<SLServer>> {
 
 #[get("/")]
 pub fn nw() -> &'static str {
-    "Northwood API wrapper. /all for all servers, /<id> for specific server. All routes require auth."
+    "Northwood API wrapper. /all for all servers, /<id> for specific server, /status for how fresh they are. All routes require a key, read:public for the statuses and read:players for the rest."
 }
 
 pub fn routes() -> Vec<Route> {
-    routes![nw_api, nw_api_all, nw, nw_api_servers]
+    routes![
+        nw_api,
+        nw_api_status,
+        nw_api_statuses,
+        nw_api_all,
+        nw,
+        nw_api_servers
+    ]
 }
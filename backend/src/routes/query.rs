@@ -1,21 +1,73 @@
 use std::{str::FromStr, sync::Arc};
 
 use crate::db::ManagedDB;
-use lurky::query::{Operator, Query, Restriction};
-use rocket::{get, response::status::NotFound, routes, serde::json::Json, Route, State, post};
+use lurky::query::{
+    Leaderboard, LeaderboardStat, NicknameMatch, NicknameQuery, Operator, Page, Query, Restriction,
+    DEFAULT_LIMIT, MAX_LIMIT,
+};
+use rocket::{
+    get,
+    http::Status,
+    response::{status::Custom, Responder},
+    routes,
+    serde::json::Json,
+    FromForm, Request, Route, State,
+};
 use serde::Serialize;
 use time::{format_description::well_known::Rfc3339, OffsetDateTime};
 
-use crate::db::DBPlayer;
+use crate::db::{DBPlayer, LeaderboardEntry, LurkyError, PlayerPage, ServerStats, Session};
 
-use super::Authenticated;
+use super::{PublicRoutes, QueryRoutes, RateLimited, ReadPlayers, RequireScope};
 
 #[derive(Serialize)]
 pub struct DBError {
     pub err: String,
 }
 
-type DBResult<T> = Result<Json<T>, NotFound<Json<DBError>>>;
+/// a LurkyError as a response, so a dead database isn't a 404
+pub struct ApiError(pub LurkyError);
+
+impl From<LurkyError> for ApiError {
+    fn from(err: LurkyError) -> Self {
+        ApiError(err)
+    }
+}
+
+impl ApiError {
+    pub fn status(&self) -> Status {
+        match self.0 {
+            LurkyError::NotFound(_) => Status::NotFound,
+            LurkyError::NotConnected => Status::ServiceUnavailable,
+            LurkyError::Conflict(_) => Status::Conflict,
+            LurkyError::InvalidQuery(_) => Status::BadRequest,
+            LurkyError::Unauthorized(_) => Status::Unauthorized,
+            LurkyError::Backend(_) => Status::InternalServerError,
+        }
+    }
+}
+
+impl From<ApiError> for Custom<Json<DBError>> {
+    fn from(err: ApiError) -> Self {
+        if let LurkyError::Backend(e) = &err.0 {
+            tracing::error!(error = ?e, "Database error");
+        }
+        Custom(
+            err.status(),
+            Json(DBError {
+                err: err.0.to_string(),
+            }),
+        )
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ApiError {
+    fn respond_to(self, req: &'r Request<'_>) -> rocket::response::Result<'static> {
+        Custom::from(self).respond_to(req)
+    }
+}
+
+pub type DBResult<T> = Result<Json<T>, ApiError>;
 
 #[get("/")]
 pub fn index() -> &'static str {
@@ -23,26 +75,44 @@ pub fn index() -> &'static str {
 }
 
 #[get("/id/<id>")]
-pub async fn query_by_id(id: u64, db: &State<Arc<ManagedDB>>) -> DBResult<DBPlayer> {
-    let player = db.get_player(id).await;
-    match player {
-        Ok(p) => Ok(Json(p)),
-        Err(_) => Err(NotFound(Json(DBError {
-            err: "Player not found!".to_string(),
-        }))),
-    }
+pub async fn query_by_id(
+    id: u64,
+    _rate: RateLimited<PublicRoutes>,
+    db: &State<Arc<ManagedDB>>,
+) -> DBResult<DBPlayer> {
+    Ok(Json(db.get_player(id).await?))
+}
+
+#[get("/id/<id>/sessions?<limit>&<offset>")]
+pub async fn query_sessions(
+    id: u64,
+    limit: Option<u64>,
+    offset: Option<u64>,
+    _rate: RateLimited<QueryRoutes>,
+    _auth: RequireScope<ReadPlayers>,
+    db: &State<Arc<ManagedDB>>,
+) -> DBResult<Vec<Session>> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+    Ok(Json(db.get_sessions(id, limit, offset.unwrap_or(0)).await?))
+}
+
+#[get("/id/<id>/servers")]
+pub async fn query_server_stats(
+    id: u64,
+    _rate: RateLimited<QueryRoutes>,
+    _auth: RequireScope<ReadPlayers>,
+    db: &State<Arc<ManagedDB>>,
+) -> DBResult<Vec<ServerStats>> {
+    Ok(Json(db.get_server_stats(id).await?))
 }
 
 #[get("/last_nick/<last_nick>")]
 pub async fn query_by_name(
     last_nick: String,
+    _rate: RateLimited<PublicRoutes>,
     db: &State<Arc<ManagedDB>>,
-) -> Result<Json<DBPlayer>, NotFound<&'static str>> {
-    let player = db.get_by_latest_nickname(&last_nick).await;
-    match player {
-        Ok(p) => Ok(Json(p)),
-        Err(_) => Err(NotFound("Player not found!")),
-    }
+) -> DBResult<DBPlayer> {
+    Ok(Json(db.get_by_latest_nickname(&last_nick).await?))
 }
 
 fn create_query_from_str<T: FromStr + Ord + Eq>(s: &str) -> Vec<Query<T>> {
@@ -124,128 +194,274 @@ fn create_date_query_from_str(s: &str) -> Vec<Query<OffsetDateTime>> {
                 _ => return None,
             };
             let str = &f[op.to_string().len()..];
-            let val = OffsetDateTime::parse(&str, &Rfc3339).ok()?;
+            let val = OffsetDateTime::parse(str, &Rfc3339).ok()?;
             Some(Query { operator: op, val })
         })
         .collect()
 }
 
-#[get("/db?<flags>&<login_amt>&<play_time>&<time_online>&<first_seen>&<last_seen>")]
-pub async fn query_db(
+#[derive(FromForm)]
+pub struct RestrictionParams {
     flags: Option<String>,
     login_amt: Option<String>,
     play_time: Option<String>,
     time_online: Option<String>,
     first_seen: Option<String>,
     last_seen: Option<String>,
-    _auth: Authenticated,
+    nick: Option<String>,
+    /// substring (default) or prefix
+    nick_match: Option<String>,
+    nick_history: Option<bool>,
+}
+
+impl RestrictionParams {
+    fn into_restriction(self) -> Result<Restriction, LurkyError> {
+        let flags = self
+            .flags
+            .map(|f| f.split(',').filter_map(|f| f.parse::<i64>().ok()).collect());
+        let mode = match self.nick_match {
+            Some(mode) => mode
+                .parse()
+                .map_err(|e: anyhow::Error| LurkyError::InvalidQuery(e.to_string()))?,
+            None => NicknameMatch::Substring,
+        };
+        let nickname = self
+            .nick
+            .filter(|n| !n.is_empty())
+            .map(|pattern| NicknameQuery {
+                pattern,
+                mode,
+                historical: self.nick_history.unwrap_or(false),
+            });
+        Ok(Restriction {
+            flags: flags.unwrap_or_default(),
+            play_time: create_duration_query_from_str(&self.play_time.unwrap_or_default()),
+            time_online: create_duration_query_from_str(&self.time_online.unwrap_or_default()),
+            login_amt: create_query_from_str(&self.login_amt.unwrap_or_default()),
+            first_seen: create_date_query_from_str(&self.first_seen.unwrap_or_default()),
+            last_seen: create_date_query_from_str(&self.last_seen.unwrap_or_default()),
+            nickname,
+        })
+    }
+}
+
+#[get("/db?<limit>&<offset>&<sort>&<rest..>")]
+pub async fn query_db(
+    limit: Option<u64>,
+    offset: Option<u64>,
+    sort: Option<String>,
+    rest: RestrictionParams,
+    _rate: RateLimited<QueryRoutes>,
+    _auth: RequireScope<ReadPlayers>,
     db: &State<Arc<ManagedDB>>,
-) -> DBResult<Vec<DBPlayer>> {
-    let flags =
-        flags.and_then(|f| Some(f.split(",").filter_map(|f| f.parse::<i64>().ok()).collect()));
-    //println!("flags: {:?}", flags);
-    let rest = Restriction {
-        flags: flags.unwrap_or_default(),
-        play_time: create_duration_query_from_str(&play_time.unwrap_or_default()),
-        time_online: create_duration_query_from_str(&time_online.unwrap_or_default()),
-        login_amt: create_query_from_str(&login_amt.unwrap_or_default()),
-        first_seen: create_date_query_from_str(&first_seen.unwrap_or_default()),
-        last_seen: create_date_query_from_str(&last_seen.unwrap_or_default()),
+) -> DBResult<PlayerPage> {
+    let rest = rest.into_restriction()?;
+    let sort = match sort {
+        Some(sort) => sort
+            .parse()
+            .map_err(|e: anyhow::Error| LurkyError::InvalidQuery(e.to_string()))?,
+        None => Default::default(),
     };
-    let players = db.get_by_restriction(&rest).await;
-    match players {
-        Ok(p) => {
-            if p.is_empty() {
-                Err(NotFound(Json(DBError {
-                    err: "No players found!".to_string(),
-                })))
-            } else {
-                Ok(Json(p))
-            }
-        }
-        Err(e) => Err(NotFound(Json(DBError { err: e.to_string() }))),
-    }
+    let page = Page {
+        limit: limit.unwrap_or(DEFAULT_LIMIT),
+        offset: offset.unwrap_or(0),
+        sort,
+    };
+    Ok(Json(db.get_by_restriction(&rest, &page).await?))
 }
 
-//first_seen = $2, last_seen = $3, play_time = $4, last_nickname = $5, nicknames = $6, flags = $7, time_online = $8, login_amt
-
-// #[post("/modifyplayer?id?field?value")]
-// pub fn modify_db_player(
-//     id: String,
-//     field: String,
-//     value: String
-// ) -> String {
-//     if !valid_keys().contains(&field)
-//     {
-        
-//     }
-//     return "default!".to_string();
-// }
-
-// pub fn valid_keys() -> Vec<String>
-// {
-//     keys![
-//         "first_seen",
-//         "last_seen",
-//         "play_time",
-//         "last_nickname",
-//         "nicknames",
-//         "flags",
-//         "time_online",
-//         "login_amt"
-//     ]
-// }
-
-
-
-#[get("/random?<flags>&<login_amt>&<play_time>&<time_online>&<first_seen>&<last_seen>")]
+#[get("/random?<rest..>")]
 pub async fn query_db_random(
-    flags: Option<String>,
-    login_amt: Option<String>,
-    play_time: Option<String>,
-    time_online: Option<String>,
-    first_seen: Option<String>,
-    last_seen: Option<String>,
-    _auth: Authenticated,
+    rest: RestrictionParams,
+    _rate: RateLimited<QueryRoutes>,
+    _auth: RequireScope<ReadPlayers>,
     db: &State<Arc<ManagedDB>>,
 ) -> DBResult<DBPlayer> {
-    let flags =
-        flags.and_then(|f| Some(f.split(",").filter_map(|f| f.parse::<i64>().ok()).collect()));
-    //println!("flags: {:?}", flags);
-    let rest = Restriction {
-        flags: flags.unwrap_or_default(),
-        play_time: create_duration_query_from_str(&play_time.unwrap_or_default()),
-        time_online: create_duration_query_from_str(&time_online.unwrap_or_default()),
-        login_amt: create_query_from_str(&login_amt.unwrap_or_default()),
-        first_seen: create_date_query_from_str(&first_seen.unwrap_or_default()),
-        last_seen: create_date_query_from_str(&last_seen.unwrap_or_default()),
+    let rest = rest.into_restriction()?;
+    Ok(Json(db.get_by_restriction_random(&rest).await?))
+}
+
+fn parse_since(s: &str) -> Option<OffsetDateTime> {
+    let period = match s {
+        "day" => time::Duration::DAY,
+        "week" => time::Duration::WEEK,
+        "month" => time::Duration::days(30),
+        s => return OffsetDateTime::parse(s, &Rfc3339).ok(),
     };
-    let players = db.get_by_restriction_random(&rest).await;
-    match players {
-        Ok(p) => Ok(Json(p)),
-        Err(e) => Err(NotFound(Json(DBError { err: e.to_string() }))),
-    }
+    Some(OffsetDateTime::now_utc() - period)
 }
 
-#[get("/leaderboard")]
-pub async fn leaderboard(db: &State<Arc<ManagedDB>>) -> DBResult<Vec<DBPlayer>> {
-    let player = db.leaderboard(20).await;
-    match player {
-        Ok(p) => Ok(Json(p)),
-        Err(error) => Err(NotFound(Json(DBError {
-            err: format!("{}", error),
-        }))),
+/// since is either rfc 3339 or day/week/month back from now.
+/// with since, by=play_time ranks the session time inside the window (sessions that started
+/// before it only count from since, open ones up to now), not the all time play_time,
+/// and by=login_amt counts the sessions started in it. players without any are left out.
+/// filtering needs a read:players key, same as /db
+#[get("/leaderboard?<by>&<limit>&<since>&<server>&<rest..>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn leaderboard(
+    by: Option<String>,
+    limit: Option<u64>,
+    since: Option<String>,
+    server: Option<u64>,
+    rest: RestrictionParams,
+    _rate: RateLimited<PublicRoutes>,
+    auth: Option<RequireScope<ReadPlayers>>,
+    db: &State<Arc<ManagedDB>>,
+) -> DBResult<Vec<LeaderboardEntry>> {
+    let by = match by {
+        Some(by) => by
+            .parse()
+            .map_err(|e: anyhow::Error| LurkyError::InvalidQuery(e.to_string()))?,
+        None => LeaderboardStat::PlayTime,
+    };
+    let since = match since {
+        Some(since) => Some(
+            parse_since(&since)
+                .ok_or_else(|| LurkyError::InvalidQuery(format!("Invalid since: {}", since)))?,
+        ),
+        None => None,
+    };
+    let restriction = rest.into_restriction()?;
+    if !restriction.is_empty() && auth.is_none() {
+        return Err(LurkyError::Unauthorized("Filtering the leaderboard".to_string()).into());
     }
+    let board = Leaderboard {
+        by,
+        limit: limit.unwrap_or(DEFAULT_LIMIT),
+        since,
+        server,
+        restriction,
+    };
+    board.check()?;
+    Ok(Json(db.leaderboard(&board).await?))
 }
 
 pub fn routes() -> Vec<Route> {
     routes![
         index,
         query_by_id,
+        query_sessions,
+        query_server_stats,
         query_by_name,
         query_db,
         query_db_random,
         leaderboard,
-        //modify_db_player
     ]
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{audit::AuthAudit, ratelimit::RateLimiter, reload::LiveConfig};
+    use lurky::config::{LogFormat, LurkyConfig, RateLimits};
+    use rocket::{http::Header, local::asynchronous::Client};
+
+    async fn client_with(connected: bool) -> Client {
+        // the sqlite one is never set up, so it isn't connected to anything
+        let (db_type, db_url) = match connected {
+            true => ("memory", ""),
+            false => ("sqlite", "sqlite://nowhere.db"),
+        };
+        let config = LurkyConfig {
+            servers: vec![],
+            auth_key: "test".to_string(),
+            db_type: db_type.to_string(),
+            db_url: db_url.to_string(),
+            refresh_cooldown: std::time::Duration::from_secs(60),
+            nw_api_url: String::new(),
+            rate_limits: RateLimits::default(),
+            http_cat_dir: String::new(),
+            log_level: String::new(),
+            log_format: LogFormat::Text,
+            trusted_proxies: vec![],
+        };
+        let db = crate::db::create_db_from_config(&config).unwrap();
+        let rocket = rocket::build()
+            .mount("/query", routes())
+            .manage(Arc::new(LiveConfig::new(config)))
+            .manage(Arc::new(db))
+            .manage(Arc::new(RateLimiter::new(RateLimits::default())))
+            .manage(Arc::new(AuthAudit::new()));
+        Client::tracked(rocket).await.unwrap()
+    }
+
+    #[rocket::async_test]
+    async fn errors_have_their_own_status() {
+        let client = client_with(true).await;
+        let res = client.get("/query/id/1").dispatch().await;
+        assert_eq!(res.status(), Status::NotFound);
+        assert_eq!(
+            res.into_string().await.unwrap(),
+            r#"{"err":"Player not found"}"#
+        );
+        let res = client
+            .get("/query/leaderboard?by=time_online&since=day")
+            .dispatch()
+            .await;
+        assert_eq!(res.status(), Status::BadRequest);
+        let res = client.get("/query/leaderboard?by=nope").dispatch().await;
+        assert_eq!(res.status(), Status::BadRequest);
+        assert_eq!(
+            res.into_string().await.unwrap(),
+            r#"{"err":"Invalid query: Unknown leaderboard stat: nope"}"#
+        );
+        let res = client.get("/query/leaderboard?since=soon").dispatch().await;
+        assert_eq!(
+            res.into_string().await.unwrap(),
+            r#"{"err":"Invalid query: Invalid since: soon"}"#
+        );
+        let res = client
+            .get("/query/leaderboard?login_amt=%3E1")
+            .dispatch()
+            .await;
+        assert_eq!(res.status(), Status::Unauthorized);
+        assert_eq!(
+            res.into_string().await.unwrap(),
+            r#"{"err":"Filtering the leaderboard needs a key!"}"#
+        );
+        let res = client
+            .get("/query/db?nick=lurk&nick_match=prefx")
+            .header(Header::new("Authorization", "Bearer test"))
+            .dispatch()
+            .await;
+        assert_eq!(res.status(), Status::BadRequest);
+        assert_eq!(
+            res.into_string().await.unwrap(),
+            r#"{"err":"Invalid query: Unknown nick_match: prefx"}"#
+        );
+        let res = client
+            .get("/query/db?sort=nope:asc")
+            .header(Header::new("Authorization", "Bearer test"))
+            .dispatch()
+            .await;
+        assert_eq!(res.status(), Status::BadRequest);
+        assert_eq!(
+            res.into_string().await.unwrap(),
+            r#"{"err":"Invalid query: Unknown sort field: nope"}"#
+        );
+
+        let client = client_with(false).await;
+        let res = client.get("/query/id/1").dispatch().await;
+        assert_eq!(res.status(), Status::ServiceUnavailable);
+        assert_eq!(
+            res.into_string().await.unwrap(),
+            r#"{"err":"Not connected to database!"}"#
+        );
+    }
+
+    #[rocket::async_test]
+    async fn player_history_needs_a_key() {
+        let client = client_with(true).await;
+        for route in ["/query/id/1/sessions", "/query/id/1/servers"] {
+            let res = client.get(route).dispatch().await;
+            assert_eq!(res.status(), Status::Unauthorized);
+            let res = client
+                .get(route)
+                .header(Header::new("Authorization", "Bearer test"))
+                .dispatch()
+                .await;
+            // nothing recorded for them yet, which isn't an error
+            assert_eq!(res.status(), Status::Ok);
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use async_trait::async_trait;
+
+use crate::northwood::SLResponse;
+
+/// somewhere the backend loop can ask what one of the servers in the config looks like right now
+#[async_trait]
+pub trait ServerSource: Send + Sync {
+    /// the server id from the config
+    fn id(&self) -> u64;
+    async fn get(&self) -> Result<SLResponse, anyhow::Error>;
+}
+
+#[cfg(test)]
+pub mod scripted {
+    use std::collections::VecDeque;
+
+    use anyhow::anyhow;
+    use async_trait::async_trait;
+    use parking_lot::Mutex;
+
+    use super::ServerSource;
+    use crate::northwood::{Player, SLResponse, SLServer};
+
+    /// plays back a fixed list of responses, one per get, for testing the backend loop offline.
+    /// once it runs out it keeps failing
+    pub struct ScriptedSource {
+        id: u64,
+        script: Mutex<VecDeque<Result<SLResponse, String>>>,
+    }
+
+    impl ScriptedSource {
+        pub fn new(id: u64, script: Vec<Result<SLResponse, String>>) -> Self {
+            Self {
+                id,
+                script: Mutex::new(script.into()),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl ServerSource for ScriptedSource {
+        fn id(&self) -> u64 {
+            self.id
+        }
+        async fn get(&self) -> Result<SLResponse, anyhow::Error> {
+            self.script
+                .lock()
+                .pop_front()
+                .unwrap_or_else(|| Err("Script ran out".to_string()))
+                .map_err(|e| anyhow!(e))
+        }
+    }
+
+    /// a response with no cooldown, servers being (id, port, player ids)
+    pub fn response(servers: &[(u64, u16, &[&str])]) -> SLResponse {
+        SLResponse {
+            cooldown: 0,
+            servers: servers
+                .iter()
+                .map(|(id, port, players)| SLServer {
+                    id: *id,
+                    port: *port,
+                    online: true,
+                    players_list: players
+                        .iter()
+                        .map(|p| Player {
+                            id: p.to_string(),
+                            nickname: Some(format!("nick {}", p)),
+                        })
+                        .collect(),
+                })
+                .collect(),
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use parking_lot::RwLock;
+use serde::Serialize;
+use time::OffsetDateTime;
+
+use crate::northwood::SLResponse;
+
+/// backoff never waits longer than this (unless refresh_cooldown itself is longer)
+const MAX_BACKOFF: time::Duration = time::Duration::hours(1);
+
+/// what we last heard from one of the servers in the config
+#[derive(Serialize, Debug, Clone)]
+pub struct ServerStatus {
+    /// the server id from the config, the one northwood knows it by
+    pub id: u64,
+    /// the last successful response
+    pub response: Option<SLResponse>,
+    /// the error of the last fetch, cleared once one succeeds
+    pub error: Option<String>,
+    /// fetches that failed in a row
+    pub failures: u32,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub last_fetch: Option<OffsetDateTime>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub last_success: Option<OffsetDateTime>,
+    /// we wont ask northwood again before this, because of their cooldown or backoff
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub next_fetch: Option<OffsetDateTime>,
+    /// no successful fetch for a while, so response can't be trusted anymore
+    pub stale: bool,
+    /// stale or the last fetch failed
+    pub degraded: bool,
+}
+
+impl ServerStatus {
+    /// nothing has been fetched yet
+    fn unknown(id: u64) -> Self {
+        ServerStatus {
+            id,
+            response: None,
+            error: None,
+            failures: 0,
+            last_fetch: None,
+            last_success: None,
+            next_fetch: None,
+            stale: true,
+            degraded: true,
+        }
+    }
+}
+
+/// the live state of every server, the backend loop writes it and everything else reads it
+#[derive(Debug)]
+pub struct ServerState {
+    servers: RwLock<BTreeMap<u64, ServerStatus>>,
+    refresh: RwLock<time::Duration>,
+}
+
+/// exponential backoff with equal jitter, jitter is between 0 and 1.
+/// failures is how many fetches failed in a row, including the last one
+pub fn backoff(base: time::Duration, failures: u32, jitter: f64) -> time::Duration {
+    let delay =
+        (base * 2f64.powi(failures.saturating_sub(1).min(16) as i32)).min(MAX_BACKOFF.max(base));
+    delay / 2 + delay / 2 * jitter.clamp(0.0, 1.0)
+}
+
+impl ServerState {
+    pub fn new(ids: impl IntoIterator<Item = u64>, refresh: time::Duration) -> Self {
+        let servers = ids
+            .into_iter()
+            .map(|id| (id, ServerStatus::unknown(id)))
+            .collect();
+        Self {
+            servers: RwLock::new(servers),
+            refresh: RwLock::new(refresh),
+        }
+    }
+    /// after a reload, new servers start out like they did at startup and removed ones are forgotten
+    pub fn reconfigure(&self, ids: impl IntoIterator<Item = u64>, refresh: time::Duration) {
+        let ids: BTreeSet<u64> = ids.into_iter().collect();
+        let mut servers = self.servers.write();
+        servers.retain(|id, _| ids.contains(id));
+        for id in ids {
+            servers
+                .entry(id)
+                .or_insert_with(|| ServerStatus::unknown(id));
+        }
+        *self.refresh.write() = refresh;
+    }
+    /// if the server can be fetched again
+    pub fn due(&self, id: u64, now: OffsetDateTime) -> bool {
+        self.servers
+            .read()
+            .get(&id)
+            .and_then(|s| s.next_fetch)
+            .is_none_or(|next| next <= now)
+    }
+    /// waits at least the cooldown northwood told us to
+    pub fn record_success(&self, id: u64, response: SLResponse, at: OffsetDateTime) {
+        if let Some(status) = self.servers.write().get_mut(&id) {
+            status.next_fetch = Some(at + time::Duration::seconds(response.cooldown as i64));
+            status.response = Some(response);
+            status.error = None;
+            status.failures = 0;
+            status.last_fetch = Some(at);
+            status.last_success = Some(at);
+        }
+    }
+    /// backs off exponentially, but never retries before retry_after if northwood gave us one
+    pub fn record_error(
+        &self,
+        id: u64,
+        error: String,
+        retry_after: Option<time::Duration>,
+        at: OffsetDateTime,
+    ) {
+        if let Some(status) = self.servers.write().get_mut(&id) {
+            status.failures += 1;
+            let delay = backoff(*self.refresh.read(), status.failures, rand::random());
+            status.next_fetch = Some(at + delay.max(retry_after.unwrap_or_default()));
+            status.error = Some(error);
+            status.last_fetch = Some(at);
+        }
+    }
+    fn with_staleness(&self, mut status: ServerStatus, now: OffsetDateTime) -> ServerStatus {
+        status.stale = match status.last_success {
+            // waiting out the cooldown after a good fetch doesn't make it stale
+            Some(_) if status.failures == 0 && status.next_fetch.is_some_and(|n| n > now) => false,
+            Some(at) => now - at > *self.refresh.read() * 2,
+            None => true,
+        };
+        status.degraded = status.stale || status.failures > 0;
+        status
+    }
+    pub fn get(&self, id: u64) -> Option<ServerStatus> {
+        let now = OffsetDateTime::now_utc();
+        self.servers
+            .read()
+            .get(&id)
+            .cloned()
+            .map(|s| self.with_staleness(s, now))
+    }
+    /// ordered by id
+    pub fn all(&self) -> Vec<ServerStatus> {
+        let now = OffsetDateTime::now_utc();
+        self.servers
+            .read()
+            .values()
+            .cloned()
+            .map(|s| self.with_staleness(s, now))
+            .collect()
+    }
+    /// the last successful responses, ordered by server id
+    pub fn responses(&self) -> Vec<SLResponse> {
+        self.servers
+            .read()
+            .values()
+            .filter_map(|s| s.response.clone())
+            .collect()
+    }
+    /// the responses that are still fresh enough to count players from
+    pub fn live_responses(&self) -> Vec<SLResponse> {
+        self.all()
+            .into_iter()
+            .filter(|s| !s.stale)
+            .filter_map(|s| s.response)
+            .collect()
+    }
+}
@@ -0,0 +1,13 @@
+-- Add migration script here
+
+CREATE TABLE sessions (
+    id bigserial PRIMARY KEY,
+    player_id bigint NOT NULL REFERENCES lurkies(id),
+    server_id bigint NOT NULL,
+    started timestamp with time zone NOT NULL,
+    ended timestamp with time zone,
+    duration bigint NOT NULL DEFAULT 0
+);
+
+CREATE INDEX sessions_player_started ON sessions (player_id, started DESC);
+CREATE INDEX sessions_open ON sessions (player_id) WHERE ended IS NULL;
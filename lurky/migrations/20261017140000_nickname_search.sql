@@ -0,0 +1,12 @@
+-- Add migration script here
+-- trigram indexes so ILIKE '%nick%' searches dont have to scan the whole table
+
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+
+-- every nickname with a newline in front, so a prefix search is ILIKE '%\nnick%'
+CREATE FUNCTION lurky_nicknames_text(nicknames varchar(32)[]) RETURNS text
+    LANGUAGE sql IMMUTABLE PARALLEL SAFE
+    AS $$ SELECT E'\n' || array_to_string(nicknames, E'\n') $$;
+
+CREATE INDEX lurkies_last_nickname_trgm ON lurkies USING gin (last_nickname gin_trgm_ops);
+CREATE INDEX lurkies_nicknames_trgm ON lurkies USING gin (lurky_nicknames_text(nicknames) gin_trgm_ops);
@@ -0,0 +1,14 @@
+-- Add migration script here
+
+CREATE TABLE server_stats (
+    player_id bigint NOT NULL REFERENCES lurkies(id),
+    server_id bigint NOT NULL,
+    port integer NOT NULL,
+    first_seen timestamp with time zone NOT NULL,
+    last_seen timestamp with time zone NOT NULL,
+    play_time bigint NOT NULL DEFAULT 0,
+    login_amt bigint NOT NULL DEFAULT 0,
+    PRIMARY KEY (player_id, server_id)
+);
+
+CREATE INDEX server_stats_server ON server_stats (server_id);
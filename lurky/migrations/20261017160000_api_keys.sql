@@ -0,0 +1,9 @@
+-- Add migration script here
+
+CREATE TABLE api_keys (
+    id bigserial PRIMARY KEY,
+    name text NOT NULL UNIQUE,
+    key_hash text NOT NULL UNIQUE,
+    scopes text[] NOT NULL,
+    created timestamp with time zone NOT NULL
+);
@@ -0,0 +1,27 @@
+-- Add migration script here
+-- dates are rfc 3339 text in utc, nicknames and flags are json arrays
+
+CREATE TABLE lurkies (
+    id INTEGER PRIMARY KEY,
+    first_seen TEXT NOT NULL,
+    last_seen TEXT NOT NULL,
+    play_time INTEGER NOT NULL,
+    last_nickname TEXT NOT NULL,
+    nicknames TEXT NOT NULL,
+    flags TEXT NOT NULL,
+    time_online INTEGER NOT NULL,
+    login_amt INTEGER NOT NULL
+);
+
+CREATE INDEX lurkies_last_nickname ON lurkies (last_nickname);
+
+CREATE TABLE sessions (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    player_id INTEGER NOT NULL REFERENCES lurkies(id),
+    server_id INTEGER NOT NULL,
+    started TEXT NOT NULL,
+    ended TEXT,
+    duration INTEGER NOT NULL DEFAULT 0
+);
+
+CREATE INDEX sessions_player_started ON sessions (player_id, started);
@@ -0,0 +1,14 @@
+-- Add migration script here
+
+CREATE TABLE server_stats (
+    player_id INTEGER NOT NULL REFERENCES lurkies(id),
+    server_id INTEGER NOT NULL,
+    port INTEGER NOT NULL,
+    first_seen TEXT NOT NULL,
+    last_seen TEXT NOT NULL,
+    play_time INTEGER NOT NULL DEFAULT 0,
+    login_amt INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (player_id, server_id)
+);
+
+CREATE INDEX server_stats_server ON server_stats (server_id);
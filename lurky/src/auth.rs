@@ -0,0 +1,88 @@
+use std::{fmt::Display, str::FromStr};
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// what an api key is allowed to do, admin can do everything
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Scope {
+    #[serde(rename = "read:public")]
+    ReadPublic,
+    #[serde(rename = "read:players")]
+    ReadPlayers,
+    #[serde(rename = "write:flags")]
+    WriteFlags,
+    #[serde(rename = "admin")]
+    Admin,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::ReadPublic => "read:public",
+            Scope::ReadPlayers => "read:players",
+            Scope::WriteFlags => "write:flags",
+            Scope::Admin => "admin",
+        }
+    }
+}
+
+impl Display for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Scope {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read:public" => Ok(Scope::ReadPublic),
+            "read:players" => Ok(Scope::ReadPlayers),
+            "write:flags" => Ok(Scope::WriteFlags),
+            "admin" => Ok(Scope::Admin),
+            s => Err(anyhow!("Unknown scope: {}", s)),
+        }
+    }
+}
+
+/// a named api key, only the hash of the key itself is ever stored
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ApiKey {
+    pub id: u64,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created: time::OffsetDateTime,
+}
+
+impl ApiKey {
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope) || self.scopes.contains(&Scope::Admin)
+    }
+}
+
+/// scopes are stored as their names, ones we don't know anymore are dropped
+pub fn parse_scopes<S: AsRef<str>>(scopes: &[S]) -> Vec<Scope> {
+    scopes
+        .iter()
+        .filter_map(|s| s.as_ref().parse().ok())
+        .collect()
+}
+
+/// a new random key, this is the only time it is seen in full
+pub fn generate_key() -> String {
+    let bytes: [u8; 32] = rand::random();
+    format!("lk_{}", hex::encode(bytes))
+}
+
+/// if key could have come from generate_key, anything else isn't worth looking up
+pub fn is_well_formed(key: &str) -> bool {
+    key.strip_prefix("lk_")
+        .is_some_and(|hex| hex.len() == 64 && hex.bytes().all(|b| b.is_ascii_hexdigit()))
+}
+
+pub fn hash_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
@@ -1,11 +1,21 @@
 use parking_lot::RwLock;
+use std::sync::atomic::{AtomicU64, Ordering};
 
-use super::{DBPlayer, DB};
-use crate::query::Restriction;
+use super::{DBPlayer, Flag, LeaderboardEntry, PlayerPage, ServerStats, Session, DB};
+use crate::auth::{ApiKey, Scope};
+use crate::error::LurkyError;
+use crate::query::{Leaderboard, LeaderboardStat, Page, Restriction};
 use rand::prelude::SliceRandom;
 #[derive(Debug)]
 pub struct MemoryDB {
     data: RwLock<Vec<DBPlayer>>,
+    sessions: RwLock<Vec<Session>>,
+    server_stats: RwLock<Vec<ServerStats>>,
+    /// (key hash, key)
+    api_keys: RwLock<Vec<(String, ApiKey)>>,
+    /// ids are never handed out twice, even after the newest key is deleted,
+    /// since the rate limiter keys its buckets on them
+    last_key_id: AtomicU64,
 }
 
 impl Clone for MemoryDB {
@@ -13,86 +23,405 @@ impl Clone for MemoryDB {
     fn clone(&self) -> Self {
         Self {
             data: RwLock::new(self.data.read().clone()),
+            sessions: RwLock::new(self.sessions.read().clone()),
+            server_stats: RwLock::new(self.server_stats.read().clone()),
+            api_keys: RwLock::new(self.api_keys.read().clone()),
+            last_key_id: AtomicU64::new(self.last_key_id.load(Ordering::SeqCst)),
         }
     }
 }
 
+impl Default for MemoryDB {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl MemoryDB {
     pub fn new() -> Self {
-        eprintln!("Using in-memory database (no persistence)");
-        eprintln!("This is not recommended for production use");
-        eprintln!("May god have mercy on your soul");
+        tracing::warn!(
+            "Using in-memory database (no persistence), this is not recommended for production use"
+        );
+        tracing::warn!("May god have mercy on your soul");
         Self {
             data: RwLock::new(Vec::new()),
+            sessions: RwLock::new(Vec::new()),
+            server_stats: RwLock::new(Vec::new()),
+            api_keys: RwLock::new(Vec::new()),
+            last_key_id: AtomicU64::new(0),
         }
     }
 }
 #[async_trait::async_trait]
 impl DB for MemoryDB {
-    async fn health(&self) -> Result<(), anyhow::Error> {
+    async fn health(&self) -> Result<(), LurkyError> {
         Ok(())
     }
-    async fn setup(&mut self) -> Result<(), anyhow::Error> {
+    async fn setup(&mut self) -> Result<(), LurkyError> {
         Ok(())
     }
-    async fn has_player(&self, player_id: u64) -> Result<bool, anyhow::Error> {
+    async fn has_player(&self, player_id: u64) -> Result<bool, LurkyError> {
         Ok(self.data.read().iter().any(|player| player.id == player_id))
     }
-    async fn get_player(&self, player_id: u64) -> Result<DBPlayer, anyhow::Error> {
+    async fn get_player(&self, player_id: u64) -> Result<DBPlayer, LurkyError> {
         self.data
             .read()
             .iter()
             .find(|player| player.id == player_id)
             .cloned()
-            .ok_or_else(|| anyhow::anyhow!("Player not found"))
+            .ok_or_else(|| LurkyError::NotFound("Player".to_string()))
     }
-    async fn create_player(&self, player: DBPlayer) -> Result<(), anyhow::Error> {
-        self.data.write().push(player);
+    async fn create_player(&self, player: DBPlayer) -> Result<(), LurkyError> {
+        let mut data = self.data.write();
+        if data.iter().any(|p| p.id == player.id) {
+            return Err(LurkyError::Conflict("Player already exists".to_string()));
+        }
+        data.push(player);
         Ok(())
     }
-    async fn update_player(&self, player: DBPlayer) -> Result<(), anyhow::Error> {
+    async fn update_player(&self, player: DBPlayer) -> Result<(), LurkyError> {
         let mut data = self.data.write();
         let index = data
             .iter()
             .position(|p| p.id == player.id)
-            .ok_or_else(|| anyhow::anyhow!("Player not found"))?;
+            .ok_or_else(|| LurkyError::NotFound("Player".to_string()))?;
         data[index] = player;
         Ok(())
     }
-    async fn get_by_latest_nickname(&self, nickname: &str) -> Result<DBPlayer, anyhow::Error> {
+    async fn get_by_latest_nickname(&self, nickname: &str) -> Result<DBPlayer, LurkyError> {
         self.data
             .read()
             .iter()
             .find(|player| player.last_nickname == nickname)
             .cloned()
-            .ok_or_else(|| anyhow::anyhow!("Player not found"))
+            .ok_or_else(|| LurkyError::NotFound("Player".to_string()))
     }
     async fn get_by_restriction(
         &self,
         restriction: &Restriction,
-    ) -> Result<Vec<DBPlayer>, anyhow::Error> {
-        Ok(self
+        page: &Page,
+    ) -> Result<PlayerPage, LurkyError> {
+        let players: Vec<DBPlayer> = self
             .data
             .read()
             .iter()
             .filter(|player| restriction.matches(player))
             .cloned()
-            .collect())
+            .collect();
+        Ok(PlayerPage {
+            total: players.len() as u64,
+            limit: page.limit(),
+            offset: page.offset,
+            players: page.apply(players),
+        })
     }
     async fn get_by_restriction_random(
         &self,
         restriction: &Restriction,
-    ) -> Result<DBPlayer, anyhow::Error> {
-        let players = self.get_by_restriction(restriction).await?;
+    ) -> Result<DBPlayer, LurkyError> {
+        let players: Vec<DBPlayer> = self
+            .data
+            .read()
+            .iter()
+            .filter(|player| restriction.matches(player))
+            .cloned()
+            .collect();
         let mut rng = rand::thread_rng();
         Ok(players
             .choose(&mut rng)
             .cloned()
-            .ok_or_else(|| anyhow::anyhow!("No players found"))?)
+            .ok_or_else(|| LurkyError::NotFound("Player".to_string()))?)
+    }
+    async fn leaderboard(&self, board: &Leaderboard) -> Result<Vec<LeaderboardEntry>, LurkyError> {
+        board.check()?;
+        let players = self
+            .data
+            .read()
+            .iter()
+            .filter(|player| board.restriction.matches(player))
+            .cloned()
+            .collect::<Vec<DBPlayer>>();
+        let mut entries: Vec<LeaderboardEntry> = match board.since {
+            None if board.server.is_some() => {
+                let stats = self.server_stats.read();
+                players
+                    .into_iter()
+                    .filter_map(|player| {
+                        let stats = stats.iter().find(|s| {
+                            s.player_id == player.id && Some(s.server_id) == board.server
+                        })?;
+                        let score = match board.by {
+                            LeaderboardStat::LoginAmt => stats.login_amt as i64,
+                            _ => stats.play_time.whole_seconds(),
+                        };
+                        Some(LeaderboardEntry { score, player })
+                    })
+                    .collect()
+            }
+            None => players
+                .into_iter()
+                .map(|player| LeaderboardEntry {
+                    score: board.by.score(&player),
+                    player,
+                })
+                .collect(),
+            Some(since) => {
+                let now = time::OffsetDateTime::now_utc();
+                let sessions = self.sessions.read();
+                players
+                    .into_iter()
+                    .filter_map(|player| {
+                        let sessions = sessions.iter().filter(|s| {
+                            s.player_id == player.id
+                                && board.server.is_none_or(|server| s.server_id == server)
+                        });
+                        let score = match board.by {
+                            LeaderboardStat::LoginAmt => {
+                                let logins = sessions.filter(|s| s.started >= since).count();
+                                if logins == 0 {
+                                    return None;
+                                }
+                                logins as i64
+                            }
+                            _ => {
+                                let overlapping: Vec<&Session> = sessions
+                                    .filter(|s| s.ended.unwrap_or(now) > since)
+                                    .collect();
+                                if overlapping.is_empty() {
+                                    return None;
+                                }
+                                overlapping
+                                    .iter()
+                                    .map(|s| {
+                                        (s.ended.unwrap_or(now) - s.started.max(since))
+                                            .as_seconds_f64()
+                                    })
+                                    .sum::<f64>()
+                                    .round() as i64
+                            }
+                        };
+                        Some(LeaderboardEntry { score, player })
+                    })
+                    .collect()
+            }
+        };
+        entries.sort_by(|a, b| b.score.cmp(&a.score).then(a.player.id.cmp(&b.player.id)));
+        entries.truncate(board.limit() as usize);
+        Ok(entries)
     }
-    async fn leaderboard(&self, limit: u64) -> Result<Vec<DBPlayer>, anyhow::Error> {
-        let mut players = self.data.read().clone();
-        players.sort_by(|a, b| b.play_time.cmp(&a.play_time));
-        Ok(players.into_iter().take(limit as usize).collect())
+    async fn get_flags(&self, player_id: u64) -> Result<Vec<Flag>, LurkyError> {
+        Ok(self.get_player(player_id).await?.flags)
+    }
+    async fn add_flag(&self, player_id: u64, flag: Flag) -> Result<(), LurkyError> {
+        let mut data = self.data.write();
+        let player = data
+            .iter_mut()
+            .find(|p| p.id == player_id)
+            .ok_or_else(|| LurkyError::NotFound("Player".to_string()))?;
+        player.flags.push(flag);
+        Ok(())
+    }
+    async fn revoke_flag(
+        &self,
+        player_id: u64,
+        flag: i64,
+        by: &str,
+        at: time::OffsetDateTime,
+    ) -> Result<(), LurkyError> {
+        let mut data = self.data.write();
+        let player = data
+            .iter_mut()
+            .find(|p| p.id == player_id)
+            .ok_or_else(|| LurkyError::NotFound("Player".to_string()))?;
+        let mut found = false;
+        for f in player.flags.iter_mut() {
+            if f.flag == flag && f.is_active() {
+                f.revoked_at = Some(at);
+                f.revoked_by = Some(by.to_string());
+                found = true;
+            }
+        }
+        if !found {
+            return Err(LurkyError::NotFound("Flag".to_string()));
+        }
+        Ok(())
+    }
+    async fn start_session(
+        &self,
+        player_id: u64,
+        server_id: u64,
+        at: time::OffsetDateTime,
+    ) -> Result<(), LurkyError> {
+        if !self.has_player(player_id).await? {
+            return Err(LurkyError::NotFound("Player".to_string()));
+        }
+        let mut sessions = self.sessions.write();
+        let id = sessions.len() as u64 + 1;
+        sessions.push(Session {
+            id,
+            player_id,
+            server_id,
+            started: at,
+            ended: None,
+            duration: time::Duration::ZERO,
+        });
+        Ok(())
+    }
+    async fn end_session(
+        &self,
+        player_id: u64,
+        at: time::OffsetDateTime,
+    ) -> Result<(), LurkyError> {
+        for session in self
+            .sessions
+            .write()
+            .iter_mut()
+            .filter(|s| s.player_id == player_id && s.ended.is_none())
+        {
+            session.ended = Some(at);
+            session.duration = at - session.started;
+        }
+        Ok(())
+    }
+    async fn end_stale_sessions(&self) -> Result<(), LurkyError> {
+        let data = self.data.read();
+        for session in self
+            .sessions
+            .write()
+            .iter_mut()
+            .filter(|s| s.ended.is_none())
+        {
+            let at = data
+                .iter()
+                .find(|p| p.id == session.player_id)
+                .map(|p| p.last_seen.max(session.started))
+                .unwrap_or(session.started);
+            session.ended = Some(at);
+            session.duration = at - session.started;
+        }
+        Ok(())
+    }
+    async fn get_sessions(
+        &self,
+        player_id: u64,
+        limit: u64,
+        offset: u64,
+    ) -> Result<Vec<Session>, LurkyError> {
+        let mut sessions: Vec<Session> = self
+            .sessions
+            .read()
+            .iter()
+            .filter(|s| s.player_id == player_id)
+            .cloned()
+            .map(|mut s| {
+                if s.ended.is_none() {
+                    s.duration = time::OffsetDateTime::now_utc() - s.started;
+                }
+                s
+            })
+            .collect();
+        sessions.sort_by_key(|s| std::cmp::Reverse((s.started, s.id)));
+        Ok(sessions
+            .into_iter()
+            .skip(offset as usize)
+            .take(limit as usize)
+            .collect())
+    }
+    async fn record_server_stats(
+        &self,
+        player_id: u64,
+        server_id: u64,
+        port: u16,
+        at: time::OffsetDateTime,
+        play_time: time::Duration,
+        login: bool,
+    ) -> Result<(), LurkyError> {
+        if !self.data.read().iter().any(|p| p.id == player_id) {
+            return Err(LurkyError::NotFound("Player".to_string()));
+        }
+        let mut stats = self.server_stats.write();
+        match stats
+            .iter_mut()
+            .find(|s| s.player_id == player_id && s.server_id == server_id)
+        {
+            Some(stats) => {
+                stats.port = port;
+                stats.last_seen = at;
+                stats.play_time += play_time;
+                stats.login_amt += login as u64;
+            }
+            None => stats.push(ServerStats {
+                player_id,
+                server_id,
+                port,
+                first_seen: at,
+                last_seen: at,
+                play_time,
+                login_amt: login as u64,
+            }),
+        }
+        Ok(())
+    }
+    async fn get_server_stats(&self, player_id: u64) -> Result<Vec<ServerStats>, LurkyError> {
+        let mut stats: Vec<ServerStats> = self
+            .server_stats
+            .read()
+            .iter()
+            .filter(|s| s.player_id == player_id)
+            .cloned()
+            .collect();
+        stats.sort_by(|a, b| {
+            b.play_time
+                .cmp(&a.play_time)
+                .then(a.server_id.cmp(&b.server_id))
+        });
+        Ok(stats)
+    }
+    async fn create_api_key(
+        &self,
+        name: &str,
+        scopes: &[Scope],
+        key_hash: &str,
+    ) -> Result<ApiKey, LurkyError> {
+        let mut keys = self.api_keys.write();
+        if keys
+            .iter()
+            .any(|(hash, key)| key.name == name || hash == key_hash)
+        {
+            return Err(LurkyError::Conflict("Key already exists".to_string()));
+        }
+        let key = ApiKey {
+            id: self.last_key_id.fetch_add(1, Ordering::SeqCst) + 1,
+            name: name.to_string(),
+            scopes: scopes.to_vec(),
+            created: time::OffsetDateTime::now_utc(),
+        };
+        keys.push((key_hash.to_string(), key.clone()));
+        Ok(key)
+    }
+    async fn get_api_key(&self, key_hash: &str) -> Result<ApiKey, LurkyError> {
+        self.api_keys
+            .read()
+            .iter()
+            .find(|(hash, _)| hash == key_hash)
+            .map(|(_, key)| key.clone())
+            .ok_or_else(|| LurkyError::NotFound("Key".to_string()))
+    }
+    async fn list_api_keys(&self) -> Result<Vec<ApiKey>, LurkyError> {
+        Ok(self
+            .api_keys
+            .read()
+            .iter()
+            .map(|(_, key)| key.clone())
+            .collect())
+    }
+    async fn delete_api_key(&self, id: u64) -> Result<(), LurkyError> {
+        let mut keys = self.api_keys.write();
+        let len = keys.len();
+        keys.retain(|(_, key)| key.id != id);
+        if keys.len() == len {
+            return Err(LurkyError::NotFound("Key".to_string()));
+        }
+        Ok(())
     }
 }
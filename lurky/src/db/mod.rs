@@ -1,7 +1,13 @@
 use std::fmt::Debug;
 pub mod mem;
 pub mod postgres;
-use crate::{config::LurkyConfig, query::Restriction};
+pub mod sqlite;
+pub use crate::error::{LurkyError, LurkyResult};
+use crate::{
+    auth::{parse_scopes, ApiKey, Scope},
+    config::LurkyConfig,
+    query::{Leaderboard, Page, Restriction},
+};
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
@@ -15,6 +21,11 @@ pub fn wrap_to_u64(x: i64) -> u64 {
 pub fn wrap_to_i64(x: u64) -> i64 {
     x.wrapping_sub(u64::MAX / 2 + 1) as i64
 }
+/// server_stats keeps login_amt as a plain count, not wrapped like the one in lurkies,
+/// because it is added to and summed in sql
+pub fn count_to_u64(x: i64) -> u64 {
+    x.max(0) as u64
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Flag {
@@ -22,6 +33,16 @@ pub struct Flag {
     pub issuer: String,
     pub issued_at: time::OffsetDateTime,
     pub comment: String,
+    /// revoked flags are kept so the history stays, they just stop counting
+    #[serde(default)]
+    pub revoked_at: Option<time::OffsetDateTime>,
+    #[serde(default)]
+    pub revoked_by: Option<String>,
+}
+impl Flag {
+    pub fn is_active(&self) -> bool {
+        self.revoked_at.is_none()
+    }
 }
 #[derive(Debug, Clone, FromRow)]
 pub struct DbRow {
@@ -83,32 +104,209 @@ impl DBPlayer {
     }
 }
 
+#[derive(Debug, Clone, FromRow)]
+pub struct SessionRow {
+    pub id: i64,
+    pub player_id: i64,
+    pub server_id: i64,
+    pub started: time::OffsetDateTime,
+    pub ended: Option<time::OffsetDateTime>,
+    pub duration: i64,
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Session {
+    pub id: u64,
+    pub player_id: u64,
+    pub server_id: u64,
+    #[serde(with = "time::serde::rfc3339")]
+    pub started: time::OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub ended: Option<time::OffsetDateTime>,
+    /// for sessions that are still open this is the time up until now
+    #[serde_as(as = "DurationSeconds<i64>")]
+    pub duration: time::Duration,
+}
+
+impl Session {
+    pub fn from_row(row: SessionRow) -> Session {
+        let duration = match row.ended {
+            Some(_) => row.duration.seconds(),
+            None => time::OffsetDateTime::now_utc() - row.started,
+        };
+        Session {
+            id: row.id as u64,
+            player_id: wrap_to_u64(row.player_id),
+            server_id: wrap_to_u64(row.server_id),
+            started: row.started,
+            ended: row.ended,
+            duration,
+        }
+    }
+}
+
+#[derive(Debug, Clone, FromRow)]
+pub struct ServerStatsRow {
+    pub player_id: i64,
+    pub server_id: i64,
+    pub port: i32,
+    pub first_seen: time::OffsetDateTime,
+    pub last_seen: time::OffsetDateTime,
+    pub play_time: i64,
+    pub login_amt: i64,
+}
+
+/// what a player did on one of the servers, server_id being the ID northwood gives it
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServerStats {
+    pub player_id: u64,
+    pub server_id: u64,
+    pub port: u16,
+    #[serde(with = "time::serde::rfc3339")]
+    pub first_seen: time::OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    pub last_seen: time::OffsetDateTime,
+    #[serde_as(as = "DurationSeconds<i64>")]
+    pub play_time: time::Duration,
+    pub login_amt: u64,
+}
+
+impl ServerStats {
+    pub fn from_row(row: ServerStatsRow) -> ServerStats {
+        ServerStats {
+            player_id: wrap_to_u64(row.player_id),
+            server_id: wrap_to_u64(row.server_id),
+            port: row.port as u16,
+            first_seen: row.first_seen,
+            last_seen: row.last_seen,
+            play_time: row.play_time.seconds(),
+            login_amt: count_to_u64(row.login_amt),
+        }
+    }
+}
+
+#[derive(Debug, Clone, FromRow)]
+pub struct ApiKeyRow {
+    pub id: i64,
+    pub name: String,
+    pub key_hash: String,
+    pub scopes: Vec<String>,
+    pub created: time::OffsetDateTime,
+}
+
+impl ApiKeyRow {
+    pub fn into_api_key(self) -> ApiKey {
+        ApiKey {
+            id: self.id as u64,
+            name: self.name,
+            scopes: parse_scopes(&self.scopes),
+            created: self.created,
+        }
+    }
+}
+
+/// one page of get_by_restriction, total is how many players matched overall
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PlayerPage {
+    pub total: u64,
+    pub limit: u64,
+    pub offset: u64,
+    pub players: Vec<DBPlayer>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LeaderboardEntry {
+    /// seconds for play_time/time_online, otherwise a count
+    pub score: i64,
+    #[serde(flatten)]
+    pub player: DBPlayer,
+}
+
 pub type ManagedDB = Box<dyn DB>;
 
 #[async_trait]
 pub trait DB: Send + Sync + Debug {
-    async fn health(&self) -> Result<(), anyhow::Error>;
-    async fn setup(&mut self) -> Result<(), anyhow::Error>;
-    async fn has_player(&self, player_id: u64) -> Result<bool, anyhow::Error>;
-    async fn get_player(&self, player_id: u64) -> Result<DBPlayer, anyhow::Error>;
-    async fn create_player(&self, player: DBPlayer) -> Result<(), anyhow::Error>;
-    async fn update_player(&self, player: DBPlayer) -> Result<(), anyhow::Error>;
-    async fn get_by_latest_nickname(&self, nickname: &str) -> Result<DBPlayer, anyhow::Error>;
+    async fn health(&self) -> Result<(), LurkyError>;
+    async fn setup(&mut self) -> Result<(), LurkyError>;
+    async fn has_player(&self, player_id: u64) -> Result<bool, LurkyError>;
+    async fn get_player(&self, player_id: u64) -> Result<DBPlayer, LurkyError>;
+    async fn create_player(&self, player: DBPlayer) -> Result<(), LurkyError>;
+    async fn update_player(&self, player: DBPlayer) -> Result<(), LurkyError>;
+    async fn get_by_latest_nickname(&self, nickname: &str) -> Result<DBPlayer, LurkyError>;
     async fn get_by_restriction(
         &self,
         restriction: &Restriction,
-    ) -> Result<Vec<DBPlayer>, anyhow::Error>;
+        page: &Page,
+    ) -> Result<PlayerPage, LurkyError>;
     async fn get_by_restriction_random(
         &self,
         restriction: &Restriction,
-    ) -> Result<DBPlayer, anyhow::Error>;
-    async fn leaderboard(&self, limit: u64) -> Result<Vec<DBPlayer>, anyhow::Error>;
+    ) -> Result<DBPlayer, LurkyError>;
+    /// highest score first, ties broken by id
+    async fn leaderboard(&self, board: &Leaderboard) -> Result<Vec<LeaderboardEntry>, LurkyError>;
+    async fn get_flags(&self, player_id: u64) -> Result<Vec<Flag>, LurkyError>;
+    async fn add_flag(&self, player_id: u64, flag: Flag) -> Result<(), LurkyError>;
+    /// marks every active flag with this id as revoked by `by` at `at`, errors if they didnt have one
+    async fn revoke_flag(
+        &self,
+        player_id: u64,
+        flag: i64,
+        by: &str,
+        at: time::OffsetDateTime,
+    ) -> Result<(), LurkyError>;
+    /// opens a new session, the player has to exist already
+    async fn start_session(
+        &self,
+        player_id: u64,
+        server_id: u64,
+        at: time::OffsetDateTime,
+    ) -> Result<(), LurkyError>;
+    /// closes whatever session the player has open, if any
+    async fn end_session(&self, player_id: u64, at: time::OffsetDateTime)
+        -> Result<(), LurkyError>;
+    /// closes sessions left open by a previous run at the players last_seen
+    async fn end_stale_sessions(&self) -> Result<(), LurkyError>;
+    /// newest first
+    async fn get_sessions(
+        &self,
+        player_id: u64,
+        limit: u64,
+        offset: u64,
+    ) -> Result<Vec<Session>, LurkyError>;
+    /// adds play_time (and a login) to what the player has on that server, seen at `at`.
+    /// the player has to exist already
+    async fn record_server_stats(
+        &self,
+        player_id: u64,
+        server_id: u64,
+        port: u16,
+        at: time::OffsetDateTime,
+        play_time: time::Duration,
+        login: bool,
+    ) -> Result<(), LurkyError>;
+    /// most played server first
+    async fn get_server_stats(&self, player_id: u64) -> Result<Vec<ServerStats>, LurkyError>;
+    /// names have to be unique
+    async fn create_api_key(
+        &self,
+        name: &str,
+        scopes: &[Scope],
+        key_hash: &str,
+    ) -> Result<ApiKey, LurkyError>;
+    /// errors if there is no key with that hash
+    async fn get_api_key(&self, key_hash: &str) -> Result<ApiKey, LurkyError>;
+    /// oldest first
+    async fn list_api_keys(&self) -> Result<Vec<ApiKey>, LurkyError>;
+    async fn delete_api_key(&self, id: u64) -> Result<(), LurkyError>;
 }
 
 pub fn create_db_from_config(config: &LurkyConfig) -> Result<ManagedDB> {
     match config.db_type.as_str() {
         "postgres" => Ok(Box::new(postgres::PostgresDB::new(config)?)),
         "memory" => Ok(Box::new(mem::MemoryDB::new())),
+        "sqlite" => Ok(Box::new(sqlite::SqliteDB::new(config)?)),
         _ => Err(anyhow!("Unknown DB type: {}", config.db_type)),
     }
 }
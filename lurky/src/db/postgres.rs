@@ -1,9 +1,34 @@
-use super::{DBPlayer, DbRow, DB};
-use crate::{db::wrap_to_i64, query::Restriction};
-use anyhow::anyhow;
+use super::{
+    ApiKeyRow, DBPlayer, DbRow, Flag, LeaderboardEntry, PlayerPage, ServerStats, ServerStatsRow,
+    Session, SessionRow, DB,
+};
+use crate::error::LurkyError;
+use crate::{
+    auth::{ApiKey, Scope},
+    db::wrap_to_i64,
+    query::{Leaderboard, LeaderboardStat, Page, Restriction},
+};
 use async_trait::async_trait;
 
-use sqlx::{postgres::PgPoolOptions, Postgres};
+use sqlx::{
+    postgres::{PgPoolOptions, PgRow},
+    FromRow, Postgres, QueryBuilder, Row,
+};
+
+/// a lurkies row with the score of a leaderboard over a period
+struct ScoredRow {
+    row: DbRow,
+    score: i64,
+}
+
+impl<'r> FromRow<'r, PgRow> for ScoredRow {
+    fn from_row(row: &'r PgRow) -> Result<Self, sqlx::Error> {
+        Ok(ScoredRow {
+            row: DbRow::from_row(row)?,
+            score: row.try_get("score")?,
+        })
+    }
+}
 
 #[derive(Debug)]
 pub struct PostgresDB {
@@ -12,7 +37,7 @@ pub struct PostgresDB {
 }
 
 impl PostgresDB {
-    pub fn new(config: &crate::config::LurkyConfig) -> Result<Self, anyhow::Error> {
+    pub fn new(config: &crate::config::LurkyConfig) -> Result<Self, LurkyError> {
         let db_url = config.db_url.clone();
         Ok(PostgresDB { pool: None, db_url })
     }
@@ -23,14 +48,14 @@ impl PostgresDB {
 
 #[async_trait]
 impl DB for PostgresDB {
-    async fn health(&self) -> Result<(), anyhow::Error> {
+    async fn health(&self) -> Result<(), LurkyError> {
         if let Some(db) = &self.pool {
             sqlx::query!("select").execute(db).await?;
             return Ok(());
         }
-        Err(anyhow!("Not connected to database!"))
+        Err(LurkyError::NotConnected)
     }
-    async fn setup(&mut self) -> Result<(), anyhow::Error> {
+    async fn setup(&mut self) -> Result<(), LurkyError> {
         if self.is_connected() {
             return Ok(());
         }
@@ -38,13 +63,13 @@ impl DB for PostgresDB {
             .max_connections(5)
             .connect(&self.db_url)
             .await?;
-        println!("Postgres: Starting migrations...");
+        tracing::info!("Postgres: Starting migrations...");
         sqlx::migrate!("./migrations").run(&pool).await?;
-        println!("Postgres: Migrations complete!");
+        tracing::info!("Postgres: Migrations complete!");
         self.pool = Some(pool);
         Ok(())
     }
-    async fn has_player(&self, player_id: u64) -> Result<bool, anyhow::Error> {
+    async fn has_player(&self, player_id: u64) -> Result<bool, LurkyError> {
         if let Some(db) = &self.pool {
             let result = sqlx::query!(
                 r#"select id from lurkies where id = $1"#,
@@ -54,9 +79,9 @@ impl DB for PostgresDB {
             .await?;
             return Ok(result.is_some());
         }
-        Err(anyhow!("Not connected to database!"))
+        Err(LurkyError::NotConnected)
     }
-    async fn get_player(&self, player_id: u64) -> Result<DBPlayer, anyhow::Error> {
+    async fn get_player(&self, player_id: u64) -> Result<DBPlayer, LurkyError> {
         if let Some(db) = &self.pool {
             let result = sqlx::query_as!(
                 DbRow,
@@ -66,12 +91,12 @@ impl DB for PostgresDB {
             .fetch_optional(db)
             .await?;
             return Ok(DBPlayer::from_row(
-                result.ok_or(anyhow!("Player not found!"))?,
+                result.ok_or_else(|| LurkyError::NotFound("Player".to_string()))?,
             ));
         }
-        Err(anyhow!("Not connected to database!"))
+        Err(LurkyError::NotConnected)
     }
-    async fn update_player(&self, player: DBPlayer) -> Result<(), anyhow::Error> {
+    async fn update_player(&self, player: DBPlayer) -> Result<(), LurkyError> {
         let row = player.to_row();
         if let Some(db) = &self.pool {
             sqlx::query!(r#"update lurkies set first_seen = $2, last_seen = $3, play_time = $4, last_nickname = $5, nicknames = $6, flags = $7, time_online = $8, login_amt = $9 where id = $1"#, row.id, row.first_seen, row.last_seen, row.play_time, row.last_nickname, &row.nicknames, row.flags, row.time_online, row.login_amt)
@@ -79,9 +104,9 @@ impl DB for PostgresDB {
                 .await?;
             return Ok(());
         }
-        Err(anyhow!("Not connected to database!"))
+        Err(LurkyError::NotConnected)
     }
-    async fn create_player(&self, player: DBPlayer) -> Result<(), anyhow::Error> {
+    async fn create_player(&self, player: DBPlayer) -> Result<(), LurkyError> {
         let row = player.to_row();
         if let Some(db) = &self.pool {
             sqlx::query!(r#"insert into lurkies (id, first_seen, last_seen, play_time, last_nickname, nicknames, flags, time_online, login_amt) values ($1, $2, $3, $4, $5, $6, $7, $8, $9)"#, row.id, row.first_seen, row.last_seen, row.play_time, row.last_nickname, &row.nicknames, row.flags, row.time_online, row.login_amt)
@@ -89,9 +114,9 @@ impl DB for PostgresDB {
                 .await?;
             return Ok(());
         }
-        Err(anyhow!("Not connected to database!"))
+        Err(LurkyError::NotConnected)
     }
-    async fn get_by_latest_nickname(&self, nickname: &str) -> Result<DBPlayer, anyhow::Error> {
+    async fn get_by_latest_nickname(&self, nickname: &str) -> Result<DBPlayer, LurkyError> {
         if let Some(db) = &self.pool {
             let result = sqlx::query_as!(
                 DbRow,
@@ -102,68 +127,339 @@ impl DB for PostgresDB {
             .await?;
             match result {
                 Some(row) => Ok(DBPlayer::from_row(row)),
-                None => Err(anyhow!("Player not found!")),
+                None => Err(LurkyError::NotFound("Player".to_string())),
             }
         } else {
-            Err(anyhow!("Not connected to database!"))
+            Err(LurkyError::NotConnected)
         }
     }
     async fn get_by_restriction(
         &self,
         restriction: &Restriction,
-    ) -> Result<Vec<DBPlayer>, anyhow::Error> {
-        let postgres_res = restriction.generate_postgres();
-        let whe = if postgres_res.len() > 0 {
-            format!("WHERE {}", postgres_res)
-        } else {
-            "".to_string()
-        };
-        let query = format!("SELECT * FROM lurkies {} LIMIT 20", whe);
+        page: &Page,
+    ) -> Result<PlayerPage, LurkyError> {
+        let mut count = QueryBuilder::<Postgres>::new("SELECT COUNT(*) FROM lurkies");
+        restriction.push_postgres(&mut count);
+        let mut query = QueryBuilder::<Postgres>::new("SELECT * FROM lurkies");
+        restriction.push_postgres(&mut query);
+        page.sort.push_postgres(&mut query);
+        query.push(" LIMIT ");
+        query.push_bind(page.limit() as i64);
+        query.push(" OFFSET ");
+        query.push_bind(page.offset as i64);
         if let Some(db) = &self.pool {
-            let result = sqlx::query_as::<Postgres, DbRow>(&query)
-                .fetch_all(db)
-                .await?;
-            return Ok(result
-                .into_iter()
-                .map(|row| DBPlayer::from_row(row))
-                .collect());
+            let (total,): (i64,) = count.build_query_as().fetch_one(db).await?;
+            let result = query.build_query_as::<DbRow>().fetch_all(db).await?;
+            return Ok(PlayerPage {
+                total: total as u64,
+                limit: page.limit(),
+                offset: page.offset,
+                players: result.into_iter().map(DBPlayer::from_row).collect(),
+            });
         }
-        Err(anyhow!("Not connected to database!"))
+        Err(LurkyError::NotConnected)
     }
     async fn get_by_restriction_random(
         &self,
         restriction: &Restriction,
-    ) -> Result<DBPlayer, anyhow::Error> {
-        let postgres_res = restriction.generate_postgres();
-        let whe = if postgres_res.len() > 0 {
-            format!("WHERE {}", postgres_res)
-        } else {
-            "".to_string()
-        };
-        let query = format!("SELECT * FROM lurkies {} ORDER BY random() LIMIT 1", whe);
+    ) -> Result<DBPlayer, LurkyError> {
+        let mut query = QueryBuilder::<Postgres>::new("SELECT * FROM lurkies");
+        restriction.push_postgres(&mut query);
+        query.push(" ORDER BY random() LIMIT 1");
         if let Some(db) = &self.pool {
-            let result = sqlx::query_as::<Postgres, DbRow>(&query)
-                .fetch_one(db)
-                .await?;
+            let result = query
+                .build_query_as::<DbRow>()
+                .fetch_optional(db)
+                .await?
+                .ok_or_else(|| LurkyError::NotFound("Player".to_string()))?;
             return Ok(DBPlayer::from_row(result));
         }
-        Err(anyhow!("Not connected to database!"))
+        Err(LurkyError::NotConnected)
     }
-    async fn leaderboard(&self, limit: u64) -> Result<Vec<DBPlayer>, anyhow::Error> {
-        let limit = if limit > 100 { 100 } else { limit };
+    async fn leaderboard(&self, board: &Leaderboard) -> Result<Vec<LeaderboardEntry>, LurkyError> {
+        board.check()?;
         if let Some(db) = &self.pool {
-            let result = sqlx::query_as!(
-                DbRow,
-                r#"select * from lurkies order by play_time desc limit $1"#,
-                limit as i64
-            )
-            .fetch_all(db)
-            .await?;
+            if board.since.is_none() && board.server.is_none() {
+                let mut query = QueryBuilder::<Postgres>::new("SELECT * FROM lurkies");
+                board.restriction.push_postgres(&mut query);
+                query.push(format!(
+                    " ORDER BY {} DESC, id ASC LIMIT ",
+                    board.by.column()
+                ));
+                query.push_bind(board.limit() as i64);
+                let result = query.build_query_as::<DbRow>().fetch_all(db).await?;
+                return Ok(result
+                    .into_iter()
+                    .map(|row| {
+                        let player = DBPlayer::from_row(row);
+                        LeaderboardEntry {
+                            score: board.by.score(&player),
+                            player,
+                        }
+                    })
+                    .collect());
+            }
+            let mut query =
+                QueryBuilder::<Postgres>::new("SELECT lurkies.*, s.score FROM lurkies JOIN (");
+            match (board.since, board.by) {
+                (None, by) => {
+                    query.push(format!(
+                        "SELECT player_id, SUM({})::bigint AS score FROM server_stats WHERE TRUE",
+                        by.column()
+                    ));
+                }
+                (Some(since), LeaderboardStat::LoginAmt) => {
+                    query.push(
+                        "SELECT player_id, COUNT(*) AS score FROM sessions WHERE started >= ",
+                    );
+                    query.push_bind(since);
+                }
+                (Some(since), _) => {
+                    let now = time::OffsetDateTime::now_utc();
+                    query.push("SELECT player_id, ROUND(SUM(EXTRACT(EPOCH FROM COALESCE(ended, ");
+                    query.push_bind(now);
+                    query.push(") - GREATEST(started, ");
+                    query.push_bind(since);
+                    query.push("))))::bigint AS score FROM sessions WHERE COALESCE(ended, ");
+                    query.push_bind(now);
+                    query.push(") > ");
+                    query.push_bind(since);
+                }
+            }
+            if let Some(server) = board.server {
+                query.push(" AND server_id = ");
+                query.push_bind(wrap_to_i64(server));
+            }
+            query.push(" GROUP BY player_id) s ON s.player_id = lurkies.id");
+            board.restriction.push_postgres(&mut query);
+            query.push(" ORDER BY s.score DESC, lurkies.id ASC LIMIT ");
+            query.push_bind(board.limit() as i64);
+            let result = query.build_query_as::<ScoredRow>().fetch_all(db).await?;
             return Ok(result
                 .into_iter()
-                .map(|row| DBPlayer::from_row(row))
+                .map(|row| LeaderboardEntry {
+                    score: row.score,
+                    player: DBPlayer::from_row(row.row),
+                })
                 .collect());
         }
-        Err(anyhow!("Not connected to database!"))
+        Err(LurkyError::NotConnected)
+    }
+    async fn get_flags(&self, player_id: u64) -> Result<Vec<Flag>, LurkyError> {
+        if let Some(db) = &self.pool {
+            let flags: Option<serde_json::Value> =
+                sqlx::query_scalar(r#"select flags from lurkies where id = $1"#)
+                    .bind(wrap_to_i64(player_id))
+                    .fetch_optional(db)
+                    .await?;
+            let flags = flags.ok_or_else(|| LurkyError::NotFound("Player".to_string()))?;
+            return Ok(serde_json::from_value(flags)?);
+        }
+        Err(LurkyError::NotConnected)
+    }
+    async fn add_flag(&self, player_id: u64, flag: Flag) -> Result<(), LurkyError> {
+        if let Some(db) = &self.pool {
+            let result = sqlx::query(r#"update lurkies set flags = flags || $2 where id = $1"#)
+                .bind(wrap_to_i64(player_id))
+                .bind(serde_json::to_value(vec![flag])?)
+                .execute(db)
+                .await?;
+            if result.rows_affected() == 0 {
+                return Err(LurkyError::NotFound("Player".to_string()));
+            }
+            return Ok(());
+        }
+        Err(LurkyError::NotConnected)
+    }
+    async fn revoke_flag(
+        &self,
+        player_id: u64,
+        flag: i64,
+        by: &str,
+        at: time::OffsetDateTime,
+    ) -> Result<(), LurkyError> {
+        if let Some(db) = &self.pool {
+            let result = sqlx::query(
+                r#"update lurkies set flags = (select jsonb_agg(case when (f->>'flag')::bigint = $2 and f->>'revoked_at' is null
+                    then f || jsonb_build_object('revoked_at', $3::jsonb, 'revoked_by', $4::text) else f end order by ord)
+                    from jsonb_array_elements(flags) with ordinality e(f, ord))
+                where id = $1 and exists (select 1 from jsonb_array_elements(flags) f where (f->>'flag')::bigint = $2 and f->>'revoked_at' is null)"#,
+            )
+            .bind(wrap_to_i64(player_id))
+            .bind(flag)
+            .bind(serde_json::to_value(at)?)
+            .bind(by)
+            .execute(db)
+            .await?;
+            if result.rows_affected() == 0 {
+                return Err(LurkyError::NotFound("Flag".to_string()));
+            }
+            return Ok(());
+        }
+        Err(LurkyError::NotConnected)
+    }
+    async fn start_session(
+        &self,
+        player_id: u64,
+        server_id: u64,
+        at: time::OffsetDateTime,
+    ) -> Result<(), LurkyError> {
+        if let Some(db) = &self.pool {
+            sqlx::query(
+                r#"insert into sessions (player_id, server_id, started) values ($1, $2, $3)"#,
+            )
+            .bind(wrap_to_i64(player_id))
+            .bind(wrap_to_i64(server_id))
+            .bind(at)
+            .execute(db)
+            .await?;
+            return Ok(());
+        }
+        Err(LurkyError::NotConnected)
+    }
+    async fn end_session(
+        &self,
+        player_id: u64,
+        at: time::OffsetDateTime,
+    ) -> Result<(), LurkyError> {
+        if let Some(db) = &self.pool {
+            sqlx::query(
+                r#"update sessions set ended = $2, duration = extract(epoch from $2 - started)::bigint where player_id = $1 and ended is null"#,
+            )
+            .bind(wrap_to_i64(player_id))
+            .bind(at)
+            .execute(db)
+            .await?;
+            return Ok(());
+        }
+        Err(LurkyError::NotConnected)
+    }
+    async fn end_stale_sessions(&self) -> Result<(), LurkyError> {
+        if let Some(db) = &self.pool {
+            sqlx::query(
+                r#"update sessions s set ended = greatest(l.last_seen, s.started), duration = extract(epoch from greatest(l.last_seen, s.started) - s.started)::bigint
+                from lurkies l where l.id = s.player_id and s.ended is null"#,
+            )
+            .execute(db)
+            .await?;
+            return Ok(());
+        }
+        Err(LurkyError::NotConnected)
+    }
+    async fn get_sessions(
+        &self,
+        player_id: u64,
+        limit: u64,
+        offset: u64,
+    ) -> Result<Vec<Session>, LurkyError> {
+        if let Some(db) = &self.pool {
+            let result = sqlx::query_as::<Postgres, SessionRow>(
+                r#"select * from sessions where player_id = $1 order by started desc, id desc limit $2 offset $3"#,
+            )
+            .bind(wrap_to_i64(player_id))
+            .bind(limit as i64)
+            .bind(offset as i64)
+            .fetch_all(db)
+            .await?;
+            return Ok(result.into_iter().map(Session::from_row).collect());
+        }
+        Err(LurkyError::NotConnected)
+    }
+    async fn record_server_stats(
+        &self,
+        player_id: u64,
+        server_id: u64,
+        port: u16,
+        at: time::OffsetDateTime,
+        play_time: time::Duration,
+        login: bool,
+    ) -> Result<(), LurkyError> {
+        if let Some(db) = &self.pool {
+            sqlx::query(
+                r#"insert into server_stats (player_id, server_id, port, first_seen, last_seen, play_time, login_amt) values ($1, $2, $3, $4, $4, $5, $6)
+                on conflict (player_id, server_id) do update set port = excluded.port, last_seen = excluded.last_seen,
+                play_time = server_stats.play_time + excluded.play_time, login_amt = server_stats.login_amt + excluded.login_amt"#,
+            )
+            .bind(wrap_to_i64(player_id))
+            .bind(wrap_to_i64(server_id))
+            .bind(port as i32)
+            .bind(at)
+            .bind(play_time.whole_seconds())
+            .bind(login as i64)
+            .execute(db)
+            .await?;
+            return Ok(());
+        }
+        Err(LurkyError::NotConnected)
+    }
+    async fn get_server_stats(&self, player_id: u64) -> Result<Vec<ServerStats>, LurkyError> {
+        if let Some(db) = &self.pool {
+            let result = sqlx::query_as::<Postgres, ServerStatsRow>(
+                r#"select * from server_stats where player_id = $1 order by play_time desc, server_id asc"#,
+            )
+            .bind(wrap_to_i64(player_id))
+            .fetch_all(db)
+            .await?;
+            return Ok(result.into_iter().map(ServerStats::from_row).collect());
+        }
+        Err(LurkyError::NotConnected)
+    }
+    async fn create_api_key(
+        &self,
+        name: &str,
+        scopes: &[Scope],
+        key_hash: &str,
+    ) -> Result<ApiKey, LurkyError> {
+        if let Some(db) = &self.pool {
+            let scopes: Vec<&str> = scopes.iter().map(Scope::as_str).collect();
+            let result = sqlx::query_as::<Postgres, ApiKeyRow>(
+                r#"insert into api_keys (name, key_hash, scopes, created) values ($1, $2, $3, $4) returning *"#,
+            )
+            .bind(name)
+            .bind(key_hash)
+            .bind(scopes)
+            .bind(time::OffsetDateTime::now_utc())
+            .fetch_one(db)
+            .await?;
+            return Ok(result.into_api_key());
+        }
+        Err(LurkyError::NotConnected)
+    }
+    async fn get_api_key(&self, key_hash: &str) -> Result<ApiKey, LurkyError> {
+        if let Some(db) = &self.pool {
+            let result = sqlx::query_as::<Postgres, ApiKeyRow>(
+                r#"select * from api_keys where key_hash = $1"#,
+            )
+            .bind(key_hash)
+            .fetch_optional(db)
+            .await?;
+            return result
+                .map(ApiKeyRow::into_api_key)
+                .ok_or_else(|| LurkyError::NotFound("Key".to_string()));
+        }
+        Err(LurkyError::NotConnected)
+    }
+    async fn list_api_keys(&self) -> Result<Vec<ApiKey>, LurkyError> {
+        if let Some(db) = &self.pool {
+            let result =
+                sqlx::query_as::<Postgres, ApiKeyRow>(r#"select * from api_keys order by id"#)
+                    .fetch_all(db)
+                    .await?;
+            return Ok(result.into_iter().map(ApiKeyRow::into_api_key).collect());
+        }
+        Err(LurkyError::NotConnected)
+    }
+    async fn delete_api_key(&self, id: u64) -> Result<(), LurkyError> {
+        if let Some(db) = &self.pool {
+            let result = sqlx::query(r#"delete from api_keys where id = $1"#)
+                .bind(id as i64)
+                .execute(db)
+                .await?;
+            if result.rows_affected() == 0 {
+                return Err(LurkyError::NotFound("Key".to_string()));
+            }
+            return Ok(());
+        }
+        Err(LurkyError::NotConnected)
     }
 }
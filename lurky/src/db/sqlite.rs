@@ -0,0 +1,635 @@
+use std::str::FromStr;
+
+use super::{DBPlayer, Flag, LeaderboardEntry, PlayerPage, ServerStats, Session, DB};
+use crate::error::LurkyError;
+use crate::{
+    auth::{parse_scopes, ApiKey, Scope},
+    db::{count_to_u64, wrap_to_i64, wrap_to_u64},
+    query::{Leaderboard, LeaderboardStat, Page, Restriction},
+};
+use async_trait::async_trait;
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions, SqliteRow},
+    FromRow, QueryBuilder, Row, Sqlite,
+};
+use time::{ext::NumericalDuration, format_description::well_known::Rfc3339, OffsetDateTime};
+
+/// lurkies as they are stored in sqlite, see migrations_sqlite
+#[derive(Debug, Clone, FromRow)]
+pub struct SqliteDbRow {
+    pub id: i64,
+    pub first_seen: String,
+    pub last_seen: String,
+    pub play_time: i64,
+    pub last_nickname: String,
+    pub nicknames: String,
+    pub flags: String,
+    pub time_online: i64,
+    pub login_amt: i64,
+}
+
+/// a lurkies row with the score of a leaderboard over a period
+struct SqliteScoredRow {
+    row: SqliteDbRow,
+    score: i64,
+}
+
+impl<'r> FromRow<'r, SqliteRow> for SqliteScoredRow {
+    fn from_row(row: &'r SqliteRow) -> Result<Self, sqlx::Error> {
+        Ok(SqliteScoredRow {
+            row: SqliteDbRow::from_row(row)?,
+            score: row.try_get("score")?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, FromRow)]
+pub struct SqliteSessionRow {
+    pub id: i64,
+    pub player_id: i64,
+    pub server_id: i64,
+    pub started: String,
+    pub ended: Option<String>,
+    pub duration: i64,
+}
+
+fn to_date_text(date: OffsetDateTime) -> String {
+    date.to_offset(time::UtcOffset::UTC)
+        .format(&Rfc3339)
+        .expect("Format date correctly")
+}
+
+fn from_date_text(date: &str) -> OffsetDateTime {
+    OffsetDateTime::parse(date, &Rfc3339).expect("Dates to be rfc 3339")
+}
+
+impl SqliteDbRow {
+    pub fn from_player(player: DBPlayer) -> Self {
+        SqliteDbRow {
+            id: wrap_to_i64(player.id),
+            first_seen: to_date_text(player.first_seen),
+            last_seen: to_date_text(player.last_seen),
+            play_time: player.play_time.whole_seconds(),
+            last_nickname: player.last_nickname,
+            nicknames: serde_json::to_string(&player.nicknames).expect("Nicknames to serialize"),
+            flags: serde_json::to_string(&player.flags).expect("Flags to serialize"),
+            time_online: player.time_online.whole_seconds(),
+            login_amt: wrap_to_i64(player.login_amt),
+        }
+    }
+    pub fn into_player(self) -> DBPlayer {
+        DBPlayer {
+            id: wrap_to_u64(self.id),
+            first_seen: from_date_text(&self.first_seen),
+            last_seen: from_date_text(&self.last_seen),
+            play_time: self.play_time.seconds(),
+            last_nickname: self.last_nickname,
+            nicknames: serde_json::from_str(&self.nicknames).expect("Nicknames to deserialize"),
+            flags: serde_json::from_str(&self.flags).expect("Flags to deserialize"),
+            time_online: self.time_online.seconds(),
+            login_amt: wrap_to_u64(self.login_amt),
+        }
+    }
+}
+
+#[derive(Debug, Clone, FromRow)]
+pub struct SqliteServerStatsRow {
+    pub player_id: i64,
+    pub server_id: i64,
+    pub port: i64,
+    pub first_seen: String,
+    pub last_seen: String,
+    pub play_time: i64,
+    pub login_amt: i64,
+}
+
+/// sqlite::memory: or a file: url with mode=memory
+fn is_memory_url(url: &str) -> bool {
+    url.trim_start_matches("sqlite:") == ":memory:" || url.contains("mode=memory")
+}
+
+impl SqliteServerStatsRow {
+    pub fn into_server_stats(self) -> ServerStats {
+        ServerStats {
+            player_id: wrap_to_u64(self.player_id),
+            server_id: wrap_to_u64(self.server_id),
+            port: self.port as u16,
+            first_seen: from_date_text(&self.first_seen),
+            last_seen: from_date_text(&self.last_seen),
+            play_time: self.play_time.seconds(),
+            login_amt: count_to_u64(self.login_amt),
+        }
+    }
+}
+
+impl SqliteSessionRow {
+    pub fn into_session(self) -> Session {
+        let started = from_date_text(&self.started);
+        let ended = self.ended.as_deref().map(from_date_text);
+        let duration = match ended {
+            Some(_) => self.duration.seconds(),
+            None => OffsetDateTime::now_utc() - started,
+        };
+        Session {
+            id: self.id as u64,
+            player_id: wrap_to_u64(self.player_id),
+            server_id: wrap_to_u64(self.server_id),
+            started,
+            ended,
+            duration,
+        }
+    }
+}
+
+/// api_keys as they are stored in sqlite, scopes is a json array
+#[derive(Debug, Clone, FromRow)]
+pub struct SqliteApiKeyRow {
+    pub id: i64,
+    pub name: String,
+    pub key_hash: String,
+    pub scopes: String,
+    pub created: String,
+}
+
+impl SqliteApiKeyRow {
+    pub fn into_api_key(self) -> ApiKey {
+        let scopes: Vec<String> =
+            serde_json::from_str(&self.scopes).expect("Scopes to deserialize");
+        ApiKey {
+            id: self.id as u64,
+            name: self.name,
+            scopes: parse_scopes(&scopes),
+            created: from_date_text(&self.created),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SqliteDB {
+    pool: Option<sqlx::Pool<sqlx::Sqlite>>,
+    db_url: String,
+}
+
+impl SqliteDB {
+    pub fn new(config: &crate::config::LurkyConfig) -> Result<Self, LurkyError> {
+        let db_url = config.db_url.clone();
+        Ok(SqliteDB { pool: None, db_url })
+    }
+    pub fn is_connected(&self) -> bool {
+        self.pool.is_some()
+    }
+}
+
+#[async_trait]
+impl DB for SqliteDB {
+    async fn health(&self) -> Result<(), LurkyError> {
+        if let Some(db) = &self.pool {
+            sqlx::query("select 1").execute(db).await?;
+            return Ok(());
+        }
+        Err(LurkyError::NotConnected)
+    }
+    async fn setup(&mut self) -> Result<(), LurkyError> {
+        if self.is_connected() {
+            return Ok(());
+        }
+        let options = SqliteConnectOptions::from_str(&self.db_url)?
+            .create_if_missing(true)
+            .foreign_keys(true);
+        // an in-memory database only lives as long as its connection, and every other
+        // connection would get an empty one of its own
+        let pool = if is_memory_url(&self.db_url) {
+            SqlitePoolOptions::new()
+                .max_connections(1)
+                .min_connections(1)
+                .idle_timeout(None)
+                .max_lifetime(None)
+        } else {
+            SqlitePoolOptions::new().max_connections(5)
+        }
+        .connect_with(options)
+        .await?;
+        tracing::info!("Sqlite: Starting migrations...");
+        sqlx::migrate!("./migrations_sqlite").run(&pool).await?;
+        tracing::info!("Sqlite: Migrations complete!");
+        self.pool = Some(pool);
+        Ok(())
+    }
+    async fn has_player(&self, player_id: u64) -> Result<bool, LurkyError> {
+        if let Some(db) = &self.pool {
+            let result: Option<i64> = sqlx::query_scalar(r#"select id from lurkies where id = ?"#)
+                .bind(wrap_to_i64(player_id))
+                .fetch_optional(db)
+                .await?;
+            return Ok(result.is_some());
+        }
+        Err(LurkyError::NotConnected)
+    }
+    async fn get_player(&self, player_id: u64) -> Result<DBPlayer, LurkyError> {
+        if let Some(db) = &self.pool {
+            let result =
+                sqlx::query_as::<Sqlite, SqliteDbRow>(r#"select * from lurkies where id = ?"#)
+                    .bind(wrap_to_i64(player_id))
+                    .fetch_optional(db)
+                    .await?;
+            return Ok(result
+                .ok_or_else(|| LurkyError::NotFound("Player".to_string()))?
+                .into_player());
+        }
+        Err(LurkyError::NotConnected)
+    }
+    async fn update_player(&self, player: DBPlayer) -> Result<(), LurkyError> {
+        let row = SqliteDbRow::from_player(player);
+        if let Some(db) = &self.pool {
+            sqlx::query(r#"update lurkies set first_seen = ?2, last_seen = ?3, play_time = ?4, last_nickname = ?5, nicknames = ?6, flags = ?7, time_online = ?8, login_amt = ?9 where id = ?1"#)
+                .bind(row.id)
+                .bind(row.first_seen)
+                .bind(row.last_seen)
+                .bind(row.play_time)
+                .bind(row.last_nickname)
+                .bind(row.nicknames)
+                .bind(row.flags)
+                .bind(row.time_online)
+                .bind(row.login_amt)
+                .execute(db)
+                .await?;
+            return Ok(());
+        }
+        Err(LurkyError::NotConnected)
+    }
+    async fn create_player(&self, player: DBPlayer) -> Result<(), LurkyError> {
+        let row = SqliteDbRow::from_player(player);
+        if let Some(db) = &self.pool {
+            sqlx::query(r#"insert into lurkies (id, first_seen, last_seen, play_time, last_nickname, nicknames, flags, time_online, login_amt) values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)"#)
+                .bind(row.id)
+                .bind(row.first_seen)
+                .bind(row.last_seen)
+                .bind(row.play_time)
+                .bind(row.last_nickname)
+                .bind(row.nicknames)
+                .bind(row.flags)
+                .bind(row.time_online)
+                .bind(row.login_amt)
+                .execute(db)
+                .await?;
+            return Ok(());
+        }
+        Err(LurkyError::NotConnected)
+    }
+    async fn get_by_latest_nickname(&self, nickname: &str) -> Result<DBPlayer, LurkyError> {
+        if let Some(db) = &self.pool {
+            let result = sqlx::query_as::<Sqlite, SqliteDbRow>(
+                r#"select * from lurkies where last_nickname = ?"#,
+            )
+            .bind(nickname)
+            .fetch_optional(db)
+            .await?;
+            match result {
+                Some(row) => Ok(row.into_player()),
+                None => Err(LurkyError::NotFound("Player".to_string())),
+            }
+        } else {
+            Err(LurkyError::NotConnected)
+        }
+    }
+    async fn get_by_restriction(
+        &self,
+        restriction: &Restriction,
+        page: &Page,
+    ) -> Result<PlayerPage, LurkyError> {
+        let mut count = QueryBuilder::<Sqlite>::new("SELECT COUNT(*) FROM lurkies");
+        restriction.push_sqlite(&mut count);
+        let mut query = QueryBuilder::<Sqlite>::new("SELECT * FROM lurkies");
+        restriction.push_sqlite(&mut query);
+        page.sort.push_sqlite(&mut query);
+        query.push(" LIMIT ");
+        query.push_bind(page.limit() as i64);
+        query.push(" OFFSET ");
+        query.push_bind(page.offset as i64);
+        if let Some(db) = &self.pool {
+            let (total,): (i64,) = count.build_query_as().fetch_one(db).await?;
+            let result = query.build_query_as::<SqliteDbRow>().fetch_all(db).await?;
+            return Ok(PlayerPage {
+                total: total as u64,
+                limit: page.limit(),
+                offset: page.offset,
+                players: result.into_iter().map(SqliteDbRow::into_player).collect(),
+            });
+        }
+        Err(LurkyError::NotConnected)
+    }
+    async fn get_by_restriction_random(
+        &self,
+        restriction: &Restriction,
+    ) -> Result<DBPlayer, LurkyError> {
+        let mut query = QueryBuilder::<Sqlite>::new("SELECT * FROM lurkies");
+        restriction.push_sqlite(&mut query);
+        query.push(" ORDER BY random() LIMIT 1");
+        if let Some(db) = &self.pool {
+            let result = query
+                .build_query_as::<SqliteDbRow>()
+                .fetch_optional(db)
+                .await?
+                .ok_or_else(|| LurkyError::NotFound("Player".to_string()))?;
+            return Ok(result.into_player());
+        }
+        Err(LurkyError::NotConnected)
+    }
+    async fn leaderboard(&self, board: &Leaderboard) -> Result<Vec<LeaderboardEntry>, LurkyError> {
+        board.check()?;
+        if let Some(db) = &self.pool {
+            if board.since.is_none() && board.server.is_none() {
+                let mut query = QueryBuilder::<Sqlite>::new("SELECT * FROM lurkies");
+                board.restriction.push_sqlite(&mut query);
+                query.push(format!(
+                    " ORDER BY {} DESC, id ASC LIMIT ",
+                    board.by.column()
+                ));
+                query.push_bind(board.limit() as i64);
+                let result = query.build_query_as::<SqliteDbRow>().fetch_all(db).await?;
+                return Ok(result
+                    .into_iter()
+                    .map(|row| {
+                        let player = row.into_player();
+                        LeaderboardEntry {
+                            score: board.by.score(&player),
+                            player,
+                        }
+                    })
+                    .collect());
+            }
+            let mut query =
+                QueryBuilder::<Sqlite>::new("SELECT lurkies.*, s.score FROM lurkies JOIN (");
+            match (board.since.map(to_date_text), board.by) {
+                (None, by) => {
+                    query.push(format!(
+                        "SELECT player_id, SUM({}) AS score FROM server_stats WHERE TRUE",
+                        by.column()
+                    ));
+                }
+                (Some(since), LeaderboardStat::LoginAmt) => {
+                    query.push("SELECT player_id, COUNT(*) AS score FROM sessions WHERE julianday(started) >= julianday(");
+                    query.push_bind(since);
+                    query.push(")");
+                }
+                (Some(since), _) => {
+                    let now = to_date_text(OffsetDateTime::now_utc());
+                    query.push("SELECT player_id, CAST(ROUND(SUM((julianday(COALESCE(ended, ");
+                    query.push_bind(now.clone());
+                    query.push(")) - MAX(julianday(started), julianday(");
+                    query.push_bind(since.clone());
+                    query.push("))) * 86400)) AS INTEGER) AS score FROM sessions WHERE julianday(COALESCE(ended, ");
+                    query.push_bind(now);
+                    query.push(")) > julianday(");
+                    query.push_bind(since);
+                    query.push(")");
+                }
+            }
+            if let Some(server) = board.server {
+                query.push(" AND server_id = ");
+                query.push_bind(wrap_to_i64(server));
+            }
+            query.push(" GROUP BY player_id) s ON s.player_id = lurkies.id");
+            board.restriction.push_sqlite(&mut query);
+            query.push(" ORDER BY s.score DESC, lurkies.id ASC LIMIT ");
+            query.push_bind(board.limit() as i64);
+            let result = query
+                .build_query_as::<SqliteScoredRow>()
+                .fetch_all(db)
+                .await?;
+            return Ok(result
+                .into_iter()
+                .map(|row| LeaderboardEntry {
+                    score: row.score,
+                    player: row.row.into_player(),
+                })
+                .collect());
+        }
+        Err(LurkyError::NotConnected)
+    }
+    async fn get_flags(&self, player_id: u64) -> Result<Vec<Flag>, LurkyError> {
+        if let Some(db) = &self.pool {
+            let flags: Option<String> =
+                sqlx::query_scalar(r#"select flags from lurkies where id = ?"#)
+                    .bind(wrap_to_i64(player_id))
+                    .fetch_optional(db)
+                    .await?;
+            let flags = flags.ok_or_else(|| LurkyError::NotFound("Player".to_string()))?;
+            return Ok(serde_json::from_str(&flags)?);
+        }
+        Err(LurkyError::NotConnected)
+    }
+    async fn add_flag(&self, player_id: u64, flag: Flag) -> Result<(), LurkyError> {
+        if let Some(db) = &self.pool {
+            let result = sqlx::query(
+                r#"update lurkies set flags = json_insert(flags, '$[#]', json(?2)) where id = ?1"#,
+            )
+            .bind(wrap_to_i64(player_id))
+            .bind(serde_json::to_string(&flag)?)
+            .execute(db)
+            .await?;
+            if result.rows_affected() == 0 {
+                return Err(LurkyError::NotFound("Player".to_string()));
+            }
+            return Ok(());
+        }
+        Err(LurkyError::NotConnected)
+    }
+    async fn revoke_flag(
+        &self,
+        player_id: u64,
+        flag: i64,
+        by: &str,
+        at: time::OffsetDateTime,
+    ) -> Result<(), LurkyError> {
+        if let Some(db) = &self.pool {
+            let result = sqlx::query(
+                r#"update lurkies set flags = (select json_group_array(case when json_extract(value, '$.flag') = ?2 and json_extract(value, '$.revoked_at') is null
+                    then json_set(value, '$.revoked_at', json(?3), '$.revoked_by', ?4) else json(value) end) from json_each(lurkies.flags))
+                where id = ?1 and exists (select 1 from json_each(lurkies.flags) where json_extract(value, '$.flag') = ?2 and json_extract(value, '$.revoked_at') is null)"#,
+            )
+            .bind(wrap_to_i64(player_id))
+            .bind(flag)
+            .bind(serde_json::to_string(&at)?)
+            .bind(by)
+            .execute(db)
+            .await?;
+            if result.rows_affected() == 0 {
+                return Err(LurkyError::NotFound("Flag".to_string()));
+            }
+            return Ok(());
+        }
+        Err(LurkyError::NotConnected)
+    }
+    async fn start_session(
+        &self,
+        player_id: u64,
+        server_id: u64,
+        at: OffsetDateTime,
+    ) -> Result<(), LurkyError> {
+        if let Some(db) = &self.pool {
+            sqlx::query(r#"insert into sessions (player_id, server_id, started) values (?, ?, ?)"#)
+                .bind(wrap_to_i64(player_id))
+                .bind(wrap_to_i64(server_id))
+                .bind(to_date_text(at))
+                .execute(db)
+                .await?;
+            return Ok(());
+        }
+        Err(LurkyError::NotConnected)
+    }
+    async fn end_session(&self, player_id: u64, at: OffsetDateTime) -> Result<(), LurkyError> {
+        if let Some(db) = &self.pool {
+            sqlx::query(
+                r#"update sessions set ended = ?2, duration = cast(round((julianday(?2) - julianday(started)) * 86400) as integer) where player_id = ?1 and ended is null"#,
+            )
+            .bind(wrap_to_i64(player_id))
+            .bind(to_date_text(at))
+            .execute(db)
+            .await?;
+            return Ok(());
+        }
+        Err(LurkyError::NotConnected)
+    }
+    async fn end_stale_sessions(&self) -> Result<(), LurkyError> {
+        if let Some(db) = &self.pool {
+            sqlx::query(
+                r#"update sessions set ended = e.at, duration = cast(round((julianday(e.at) - julianday(sessions.started)) * 86400) as integer)
+                from (select s.id, case when julianday(l.last_seen) > julianday(s.started) then l.last_seen else s.started end as at
+                    from sessions s join lurkies l on l.id = s.player_id where s.ended is null) e
+                where sessions.id = e.id"#,
+            )
+            .execute(db)
+            .await?;
+            return Ok(());
+        }
+        Err(LurkyError::NotConnected)
+    }
+    async fn get_sessions(
+        &self,
+        player_id: u64,
+        limit: u64,
+        offset: u64,
+    ) -> Result<Vec<Session>, LurkyError> {
+        if let Some(db) = &self.pool {
+            let result = sqlx::query_as::<Sqlite, SqliteSessionRow>(
+                r#"select * from sessions where player_id = ? order by julianday(started) desc, id desc limit ? offset ?"#,
+            )
+            .bind(wrap_to_i64(player_id))
+            .bind(limit as i64)
+            .bind(offset as i64)
+            .fetch_all(db)
+            .await?;
+            return Ok(result
+                .into_iter()
+                .map(SqliteSessionRow::into_session)
+                .collect());
+        }
+        Err(LurkyError::NotConnected)
+    }
+    async fn record_server_stats(
+        &self,
+        player_id: u64,
+        server_id: u64,
+        port: u16,
+        at: OffsetDateTime,
+        play_time: time::Duration,
+        login: bool,
+    ) -> Result<(), LurkyError> {
+        if let Some(db) = &self.pool {
+            sqlx::query(
+                r#"insert into server_stats (player_id, server_id, port, first_seen, last_seen, play_time, login_amt) values (?1, ?2, ?3, ?4, ?4, ?5, ?6)
+                on conflict (player_id, server_id) do update set port = excluded.port, last_seen = excluded.last_seen,
+                play_time = server_stats.play_time + excluded.play_time, login_amt = server_stats.login_amt + excluded.login_amt"#,
+            )
+            .bind(wrap_to_i64(player_id))
+            .bind(wrap_to_i64(server_id))
+            .bind(port as i64)
+            .bind(to_date_text(at))
+            .bind(play_time.whole_seconds())
+            .bind(login as i64)
+            .execute(db)
+            .await?;
+            return Ok(());
+        }
+        Err(LurkyError::NotConnected)
+    }
+    async fn get_server_stats(&self, player_id: u64) -> Result<Vec<ServerStats>, LurkyError> {
+        if let Some(db) = &self.pool {
+            let result = sqlx::query_as::<Sqlite, SqliteServerStatsRow>(
+                r#"select * from server_stats where player_id = ? order by play_time desc, server_id asc"#,
+            )
+            .bind(wrap_to_i64(player_id))
+            .fetch_all(db)
+            .await?;
+            return Ok(result
+                .into_iter()
+                .map(SqliteServerStatsRow::into_server_stats)
+                .collect());
+        }
+        Err(LurkyError::NotConnected)
+    }
+    async fn create_api_key(
+        &self,
+        name: &str,
+        scopes: &[Scope],
+        key_hash: &str,
+    ) -> Result<ApiKey, LurkyError> {
+        if let Some(db) = &self.pool {
+            let scopes: Vec<&str> = scopes.iter().map(Scope::as_str).collect();
+            // insert .. returning with fetch_one leaves the statement open in sqlite,
+            // so the key is read back instead
+            sqlx::query(
+                r#"insert into api_keys (name, key_hash, scopes, created) values (?, ?, ?, ?)"#,
+            )
+            .bind(name)
+            .bind(key_hash)
+            .bind(serde_json::to_string(&scopes)?)
+            .bind(to_date_text(OffsetDateTime::now_utc()))
+            .execute(db)
+            .await?;
+            return self.get_api_key(key_hash).await;
+        }
+        Err(LurkyError::NotConnected)
+    }
+    async fn get_api_key(&self, key_hash: &str) -> Result<ApiKey, LurkyError> {
+        if let Some(db) = &self.pool {
+            let result = sqlx::query_as::<Sqlite, SqliteApiKeyRow>(
+                r#"select * from api_keys where key_hash = ?"#,
+            )
+            .bind(key_hash)
+            .fetch_optional(db)
+            .await?;
+            return result
+                .map(SqliteApiKeyRow::into_api_key)
+                .ok_or_else(|| LurkyError::NotFound("Key".to_string()));
+        }
+        Err(LurkyError::NotConnected)
+    }
+    async fn list_api_keys(&self) -> Result<Vec<ApiKey>, LurkyError> {
+        if let Some(db) = &self.pool {
+            let result =
+                sqlx::query_as::<Sqlite, SqliteApiKeyRow>(r#"select * from api_keys order by id"#)
+                    .fetch_all(db)
+                    .await?;
+            return Ok(result
+                .into_iter()
+                .map(SqliteApiKeyRow::into_api_key)
+                .collect());
+        }
+        Err(LurkyError::NotConnected)
+    }
+    async fn delete_api_key(&self, id: u64) -> Result<(), LurkyError> {
+        if let Some(db) = &self.pool {
+            let result = sqlx::query(r#"delete from api_keys where id = ?"#)
+                .bind(id as i64)
+                .execute(db)
+                .await?;
+            if result.rows_affected() == 0 {
+                return Err(LurkyError::NotFound("Key".to_string()));
+            }
+            return Ok(());
+        }
+        Err(LurkyError::NotConnected)
+    }
+}
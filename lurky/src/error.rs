@@ -0,0 +1,58 @@
+use thiserror::Error;
+
+/// what can go wrong talking to a database, so callers can tell a missing player from a dead database
+#[derive(Error, Debug)]
+pub enum LurkyError {
+    /// what wasn't found, "Player" becomes "Player not found"
+    #[error("{0} not found")]
+    NotFound(String),
+    #[error("Not connected to database!")]
+    NotConnected,
+    /// something with the same unique name or id is already there
+    #[error("{0}")]
+    Conflict(String),
+    #[error("Invalid query: {0}")]
+    InvalidQuery(String),
+    /// what a key is needed for, "Filtering" becomes "Filtering needs a key!"
+    #[error("{0} needs a key!")]
+    Unauthorized(String),
+    #[error("Database error: {0}")]
+    Backend(anyhow::Error),
+}
+
+pub type LurkyResult<T> = Result<T, LurkyError>;
+
+/// unique violations in postgres and sqlite (a unique index and a primary key)
+const UNIQUE_VIOLATIONS: [&str; 3] = ["23505", "2067", "1555"];
+
+impl From<sqlx::Error> for LurkyError {
+    fn from(err: sqlx::Error) -> Self {
+        // RowNotFound isn't a NotFound, queries that can come back empty use fetch_optional
+        // and say what wasn't there
+        match err {
+            sqlx::Error::PoolTimedOut
+            | sqlx::Error::PoolClosed
+            | sqlx::Error::WorkerCrashed
+            | sqlx::Error::Io(_) => LurkyError::NotConnected,
+            sqlx::Error::Database(e)
+                if e.code()
+                    .is_some_and(|code| UNIQUE_VIOLATIONS.contains(&code.as_ref())) =>
+            {
+                LurkyError::Conflict(e.message().to_string())
+            }
+            err => LurkyError::Backend(err.into()),
+        }
+    }
+}
+
+impl From<sqlx::migrate::MigrateError> for LurkyError {
+    fn from(err: sqlx::migrate::MigrateError) -> Self {
+        LurkyError::Backend(err.into())
+    }
+}
+
+impl From<serde_json::Error> for LurkyError {
+    fn from(err: serde_json::Error) -> Self {
+        LurkyError::Backend(err.into())
+    }
+}
@@ -1,3 +1,6 @@
+pub mod auth;
 pub mod config;
 pub mod db;
+pub mod error;
+pub mod logging;
 pub mod query;
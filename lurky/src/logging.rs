@@ -0,0 +1,50 @@
+use anyhow::anyhow;
+use tracing_subscriber::EnvFilter;
+
+use crate::config::{LogFormat, LurkyConfig};
+
+type SetLevel = dyn Fn(&str) -> Result<(), anyhow::Error> + Send + Sync;
+
+/// changes the filter of the subscriber init set up, so a reloaded log_level takes effect
+pub struct LogLevel(Box<SetLevel>);
+
+impl LogLevel {
+    pub fn new(set: impl Fn(&str) -> Result<(), anyhow::Error> + Send + Sync + 'static) -> Self {
+        Self(Box::new(set))
+    }
+    /// level is a log_level, the same directives as RUST_LOG
+    pub fn set(&self, level: &str) -> Result<(), anyhow::Error> {
+        (self.0)(level)
+    }
+}
+
+/// sets up the global subscriber from log_level and log_format, call it once the config is loaded.
+/// the `log` crate (rocket, sqlx) is forwarded into it too. log_format can't change after this
+pub fn init(config: &LurkyConfig) -> Result<LogLevel, anyhow::Error> {
+    let filter = EnvFilter::try_new(&config.log_level)?;
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    let failed = |e| anyhow!("Failed to set up logging: {}", e);
+    match config.log_format {
+        LogFormat::Text => {
+            let builder = builder.with_filter_reloading();
+            let handle = builder.reload_handle();
+            builder.try_init().map_err(failed)?;
+            Ok(LogLevel::new(move |level| {
+                Ok(handle.reload(EnvFilter::try_new(level)?)?)
+            }))
+        }
+        // one object per line, with the spans the event happened in
+        LogFormat::Json => {
+            let builder = builder
+                .json()
+                .with_current_span(false)
+                .with_span_list(true)
+                .with_filter_reloading();
+            let handle = builder.reload_handle();
+            builder.try_init().map_err(failed)?;
+            Ok(LogLevel::new(move |level| {
+                Ok(handle.reload(EnvFilter::try_new(level)?)?)
+            }))
+        }
+    }
+}
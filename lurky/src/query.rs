@@ -1,6 +1,12 @@
 use time::format_description::well_known::Rfc3339;
 
+use std::{cmp::Ordering, str::FromStr};
+
+use anyhow::anyhow;
+use sqlx::{Postgres, QueryBuilder, Sqlite};
+
 use crate::db::{wrap_to_i64, DBPlayer};
+use crate::error::LurkyError;
 
 pub enum Operator {
     LessThan,
@@ -11,16 +17,16 @@ pub enum Operator {
     GreaterThanEqualTo,
 }
 
-impl ToString for Operator {
-    fn to_string(&self) -> String {
-        match self {
-            Operator::LessThan => "<".to_string(),
-            Operator::GreaterThan => ">".to_string(),
-            Operator::EqualTo => "=".to_string(),
-            Operator::NotEqualTo => "!=".to_string(),
-            Operator::LessThanEqualTo => "<=".to_string(),
-            Operator::GreaterThanEqualTo => ">=".to_string(),
-        }
+impl std::fmt::Display for Operator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Operator::LessThan => "<",
+            Operator::GreaterThan => ">",
+            Operator::EqualTo => "=",
+            Operator::NotEqualTo => "!=",
+            Operator::LessThanEqualTo => "<=",
+            Operator::GreaterThanEqualTo => ">=",
+        })
     }
 }
 pub struct Query<T: Ord + Eq> {
@@ -40,6 +46,298 @@ impl<T: Ord + Eq> Query<T> {
         }
     }
 }
+pub enum NicknameMatch {
+    Substring,
+    Prefix,
+}
+
+impl FromStr for NicknameMatch {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "substring" => Ok(NicknameMatch::Substring),
+            "prefix" => Ok(NicknameMatch::Prefix),
+            s => Err(anyhow!("Unknown nick_match: {}", s)),
+        }
+    }
+}
+
+/// case insensitive nickname search.
+/// only ascii letters are folded on every backend, sqlite's LIKE leaves the rest alone,
+/// while postgres and memory fold them too. a pattern in the same case always matches
+pub struct NicknameQuery {
+    pub pattern: String,
+    pub mode: NicknameMatch,
+    /// also look through every nickname the player has had, not just the last one
+    pub historical: bool,
+}
+
+impl NicknameQuery {
+    fn matches_one(&self, nickname: &str) -> bool {
+        let nickname = nickname.to_lowercase();
+        let pattern = self.pattern.to_lowercase();
+        match self.mode {
+            NicknameMatch::Substring => nickname.contains(&pattern),
+            NicknameMatch::Prefix => nickname.starts_with(&pattern),
+        }
+    }
+    pub fn matches(&self, player: &DBPlayer) -> bool {
+        self.matches_one(&player.last_nickname)
+            || (self.historical && player.nicknames.iter().any(|n| self.matches_one(n)))
+    }
+    /// the pattern with LIKE wildcards escaped with a backslash
+    fn escaped(&self) -> String {
+        let mut escaped = String::with_capacity(self.pattern.len());
+        for ch in self.pattern.chars() {
+            if matches!(ch, '\\' | '%' | '_') {
+                escaped.push('\\');
+            }
+            escaped.push(ch);
+        }
+        escaped
+    }
+    /// LIKE pattern for a single nickname
+    fn like_pattern(&self) -> String {
+        match self.mode {
+            NicknameMatch::Substring => format!("%{}%", self.escaped()),
+            NicknameMatch::Prefix => format!("{}%", self.escaped()),
+        }
+    }
+}
+
+pub const DEFAULT_LIMIT: u64 = 20;
+pub const MAX_LIMIT: u64 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortField {
+    Id,
+    FirstSeen,
+    LastSeen,
+    PlayTime,
+    TimeOnline,
+    LoginAmt,
+    LastNickname,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+/// `<field>:<asc|desc>`, the order defaults to asc
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sort {
+    pub field: SortField,
+    pub order: SortOrder,
+}
+
+impl FromStr for Sort {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, ':');
+        let field = match parts.next().unwrap_or_default() {
+            "id" => SortField::Id,
+            "first_seen" => SortField::FirstSeen,
+            "last_seen" => SortField::LastSeen,
+            "play_time" => SortField::PlayTime,
+            "time_online" => SortField::TimeOnline,
+            "login_amt" => SortField::LoginAmt,
+            "last_nickname" => SortField::LastNickname,
+            f => return Err(anyhow!("Unknown sort field: {}", f)),
+        };
+        let order = match parts.next() {
+            None | Some("asc") => SortOrder::Asc,
+            Some("desc") => SortOrder::Desc,
+            Some(o) => return Err(anyhow!("Unknown sort order: {}", o)),
+        };
+        Ok(Sort { field, order })
+    }
+}
+
+impl Default for Sort {
+    fn default() -> Self {
+        Sort {
+            field: SortField::Id,
+            order: SortOrder::Asc,
+        }
+    }
+}
+
+impl Sort {
+    pub fn compare(&self, a: &DBPlayer, b: &DBPlayer) -> Ordering {
+        let ord = match self.field {
+            SortField::Id => a.id.cmp(&b.id),
+            SortField::FirstSeen => a.first_seen.cmp(&b.first_seen),
+            SortField::LastSeen => a.last_seen.cmp(&b.last_seen),
+            SortField::PlayTime => a.play_time.cmp(&b.play_time),
+            SortField::TimeOnline => a.time_online.cmp(&b.time_online),
+            SortField::LoginAmt => a.login_amt.cmp(&b.login_amt),
+            SortField::LastNickname => a.last_nickname.cmp(&b.last_nickname),
+        };
+        let ord = match self.order {
+            SortOrder::Asc => ord,
+            SortOrder::Desc => ord.reverse(),
+        };
+        // ties are always broken by id so pages dont overlap
+        ord.then(a.id.cmp(&b.id))
+    }
+    fn direction(&self) -> &'static str {
+        match self.order {
+            SortOrder::Asc => "ASC",
+            SortOrder::Desc => "DESC",
+        }
+    }
+    /// ids and login_amt are stored wrapped, which keeps their order intact
+    pub fn push_postgres(&self, query: &mut QueryBuilder<'_, Postgres>) {
+        let column = match self.field {
+            SortField::Id => "id",
+            SortField::FirstSeen => "first_seen",
+            SortField::LastSeen => "last_seen",
+            SortField::PlayTime => "play_time",
+            SortField::TimeOnline => "time_online",
+            SortField::LoginAmt => "login_amt",
+            // byte order, the same as rust compares strings
+            SortField::LastNickname => "last_nickname COLLATE \"C\"",
+        };
+        query.push(format_args!(
+            " ORDER BY {} {}, id ASC",
+            column,
+            self.direction()
+        ));
+    }
+    pub fn push_sqlite(&self, query: &mut QueryBuilder<'_, Sqlite>) {
+        let column = match self.field {
+            SortField::Id => "id",
+            SortField::FirstSeen => "julianday(first_seen)",
+            SortField::LastSeen => "julianday(last_seen)",
+            SortField::PlayTime => "play_time",
+            SortField::TimeOnline => "time_online",
+            SortField::LoginAmt => "login_amt",
+            SortField::LastNickname => "last_nickname",
+        };
+        query.push(format_args!(
+            " ORDER BY {} {}, id ASC",
+            column,
+            self.direction()
+        ));
+    }
+}
+
+/// which slice of the matching players to return
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Page {
+    pub limit: u64,
+    pub offset: u64,
+    pub sort: Sort,
+}
+
+impl Default for Page {
+    fn default() -> Self {
+        Page {
+            limit: DEFAULT_LIMIT,
+            offset: 0,
+            sort: Sort::default(),
+        }
+    }
+}
+
+impl Page {
+    /// limit clamped to MAX_LIMIT
+    pub fn limit(&self) -> u64 {
+        self.limit.min(MAX_LIMIT)
+    }
+    pub fn apply(&self, mut players: Vec<DBPlayer>) -> Vec<DBPlayer> {
+        players.sort_by(|a, b| self.sort.compare(a, b));
+        players
+            .into_iter()
+            .skip(self.offset as usize)
+            .take(self.limit() as usize)
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LeaderboardStat {
+    #[default]
+    PlayTime,
+    LoginAmt,
+    TimeOnline,
+}
+
+impl FromStr for LeaderboardStat {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "play_time" => Ok(LeaderboardStat::PlayTime),
+            "login_amt" => Ok(LeaderboardStat::LoginAmt),
+            "time_online" => Ok(LeaderboardStat::TimeOnline),
+            s => Err(anyhow!("Unknown leaderboard stat: {}", s)),
+        }
+    }
+}
+
+impl LeaderboardStat {
+    pub fn column(&self) -> &'static str {
+        match self {
+            LeaderboardStat::PlayTime => "play_time",
+            LeaderboardStat::LoginAmt => "login_amt",
+            LeaderboardStat::TimeOnline => "time_online",
+        }
+    }
+    /// the all time score
+    pub fn score(&self, player: &DBPlayer) -> i64 {
+        match self {
+            LeaderboardStat::PlayTime => player.play_time.whole_seconds(),
+            LeaderboardStat::LoginAmt => player.login_amt as i64,
+            LeaderboardStat::TimeOnline => player.time_online.whole_seconds(),
+        }
+    }
+}
+
+pub struct Leaderboard {
+    pub by: LeaderboardStat,
+    pub limit: u64,
+    /// only count what happened since then, worked out from the sessions table.
+    /// play_time is then the time spent in sessions (alone or not), and login_amt the sessions started
+    pub since: Option<time::OffsetDateTime>,
+    /// only count what happened on this server, from server_stats or the sessions on it with since
+    pub server: Option<u64>,
+    pub restriction: Restriction,
+}
+
+impl Default for Leaderboard {
+    fn default() -> Self {
+        Leaderboard {
+            by: LeaderboardStat::PlayTime,
+            limit: DEFAULT_LIMIT,
+            since: None,
+            server: None,
+            restriction: Restriction::default(),
+        }
+    }
+}
+
+impl Leaderboard {
+    pub fn limit(&self) -> u64 {
+        self.limit.min(MAX_LIMIT)
+    }
+    pub fn check(&self) -> Result<(), LurkyError> {
+        if self.since.is_some() && self.by == LeaderboardStat::TimeOnline {
+            return Err(LurkyError::InvalidQuery(
+                "time_online can't be limited to a period".to_string(),
+            ));
+        }
+        if self.server.is_some() && self.by == LeaderboardStat::TimeOnline {
+            return Err(LurkyError::InvalidQuery(
+                "time_online can't be limited to a server".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
 pub struct Restriction {
     pub flags: Vec<i64>,
     pub play_time: Vec<Query<time::Duration>>,
@@ -47,11 +345,16 @@ pub struct Restriction {
     pub login_amt: Vec<Query<u64>>,
     pub first_seen: Vec<Query<time::OffsetDateTime>>,
     pub last_seen: Vec<Query<time::OffsetDateTime>>,
+    pub nickname: Option<NicknameQuery>,
 }
 impl Restriction {
     pub fn matches(&self, player: &DBPlayer) -> bool {
         for flag in &self.flags {
-            if !player.flags.iter().any(|f| f.flag == *flag) {
+            if !player
+                .flags
+                .iter()
+                .any(|f| f.flag == *flag && f.is_active())
+            {
                 return false;
             }
         }
@@ -80,51 +383,165 @@ impl Restriction {
                 return false;
             }
         }
+        if let Some(nickname) = &self.nickname {
+            if !nickname.matches(player) {
+                return false;
+            }
+        }
         true
     }
-    pub fn generate_postgres(&self) -> String {
-        //let mut query = String::new();
-        let mut queries = vec![];
-        queries.extend(
-            self.flags
-                .iter()
-                .map(|flag| format!("jsonb_path_query_array(flags, '$[*].flag') @> '{}'", flag)),
+    pub fn is_empty(&self) -> bool {
+        self.flags.is_empty()
+            && self.play_time.is_empty()
+            && self.time_online.is_empty()
+            && self.login_amt.is_empty()
+            && self.first_seen.is_empty()
+            && self.last_seen.is_empty()
+            && self.nickname.is_none()
+    }
+    /// appends a WHERE clause (if there is anything to restrict) to the query, every value is bound
+    pub fn push_postgres(&self, query: &mut QueryBuilder<'_, Postgres>) {
+        if self.is_empty() {
+            return;
+        }
+        query.push(" WHERE ");
+        let mut queries = query.separated(" AND ");
+        for flag in &self.flags {
+            queries.push(
+                "EXISTS (SELECT 1 FROM jsonb_array_elements(flags) f WHERE f->>'revoked_at' IS NULL AND (f->>'flag')::bigint = ",
+            );
+            queries.push_bind_unseparated(*flag);
+            queries.push_unseparated(")");
+        }
+        for query in &self.play_time {
+            queries.push(format_args!("play_time {} ", query.operator));
+            queries.push_bind_unseparated(query.val.whole_seconds());
+        }
+        for query in &self.time_online {
+            queries.push(format_args!("time_online {} ", query.operator));
+            queries.push_bind_unseparated(query.val.whole_seconds());
+        }
+        for query in &self.login_amt {
+            queries.push(format_args!("login_amt {} ", query.operator));
+            queries.push_bind_unseparated(wrap_to_i64(query.val));
+        }
+        for query in &self.first_seen {
+            queries.push(format_args!("first_seen {} ", query.operator));
+            queries.push_bind_unseparated(query.val);
+        }
+        for query in &self.last_seen {
+            queries.push(format_args!("last_seen {} ", query.operator));
+            queries.push_bind_unseparated(query.val);
+        }
+        if let Some(nickname) = &self.nickname {
+            // both of these are backed by trigram indexes, see the nickname_search migration
+            queries.push("(last_nickname ILIKE ");
+            queries.push_bind_unseparated(nickname.like_pattern());
+            if nickname.historical {
+                // lurky_nicknames_text puts a newline in front of every nickname
+                let pattern = match nickname.mode {
+                    NicknameMatch::Substring => format!("%{}%", nickname.escaped()),
+                    NicknameMatch::Prefix => format!("%\n{}%", nickname.escaped()),
+                };
+                queries.push_unseparated(" OR lurky_nicknames_text(nicknames) ILIKE ");
+                queries.push_bind_unseparated(pattern);
+            }
+            queries.push_unseparated(")");
+        }
+    }
+    /// same thing as push_postgres, but for the sqlite schema (json text columns, rfc 3339 text dates)
+    pub fn push_sqlite(&self, query: &mut QueryBuilder<'_, Sqlite>) {
+        if self.is_empty() {
+            return;
+        }
+        query.push(" WHERE ");
+        let mut queries = query.separated(" AND ");
+        for flag in &self.flags {
+            queries.push(
+                "EXISTS (SELECT 1 FROM json_each(flags) WHERE json_extract(value, '$.revoked_at') IS NULL AND json_extract(value, '$.flag') = ",
+            );
+            queries.push_bind_unseparated(*flag);
+            queries.push_unseparated(")");
+        }
+        for query in &self.play_time {
+            queries.push(format_args!("play_time {} ", query.operator));
+            queries.push_bind_unseparated(query.val.whole_seconds());
+        }
+        for query in &self.time_online {
+            queries.push(format_args!("time_online {} ", query.operator));
+            queries.push_bind_unseparated(query.val.whole_seconds());
+        }
+        for query in &self.login_amt {
+            queries.push(format_args!("login_amt {} ", query.operator));
+            queries.push_bind_unseparated(wrap_to_i64(query.val));
+        }
+        for query in &self.first_seen {
+            queries.push(format_args!(
+                "julianday(first_seen) {} julianday(",
+                query.operator
+            ));
+            queries
+                .push_bind_unseparated(query.val.format(&Rfc3339).expect("Format date correctly"));
+            queries.push_unseparated(")");
+        }
+        for query in &self.last_seen {
+            queries.push(format_args!(
+                "julianday(last_seen) {} julianday(",
+                query.operator
+            ));
+            queries
+                .push_bind_unseparated(query.val.format(&Rfc3339).expect("Format date correctly"));
+            queries.push_unseparated(")");
+        }
+        if let Some(nickname) = &self.nickname {
+            // sqlite's LIKE is already case insensitive, but only for ascii, see NicknameQuery
+            queries.push("(last_nickname LIKE ");
+            queries.push_bind_unseparated(nickname.like_pattern());
+            queries.push_unseparated(" ESCAPE '\\'");
+            if nickname.historical {
+                queries.push_unseparated(
+                    " OR EXISTS (SELECT 1 FROM json_each(nicknames) WHERE value LIKE ",
+                );
+                queries.push_bind_unseparated(nickname.like_pattern());
+                queries.push_unseparated(" ESCAPE '\\')");
+            }
+            queries.push_unseparated(")");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_are_bound() {
+        let rest = Restriction {
+            flags: vec![1],
+            login_amt: vec![Query {
+                operator: Operator::GreaterThanEqualTo,
+                val: 100,
+            }],
+            first_seen: vec![Query {
+                operator: Operator::LessThan,
+                val: time::OffsetDateTime::UNIX_EPOCH,
+            }],
+            ..Default::default()
+        };
+        let mut query = QueryBuilder::<Postgres>::new("SELECT * FROM lurkies");
+        rest.push_postgres(&mut query);
+        assert_eq!(
+            query.sql(),
+            "SELECT * FROM lurkies WHERE EXISTS (SELECT 1 FROM jsonb_array_elements(flags) f WHERE f->>'revoked_at' IS NULL AND (f->>'flag')::bigint = $1) AND login_amt >= $2 AND first_seen < $3"
+        );
+        let mut query = QueryBuilder::<Sqlite>::new("SELECT * FROM lurkies");
+        rest.push_sqlite(&mut query);
+        assert_eq!(
+            query.sql(),
+            "SELECT * FROM lurkies WHERE EXISTS (SELECT 1 FROM json_each(flags) WHERE json_extract(value, '$.revoked_at') IS NULL AND json_extract(value, '$.flag') = ?) AND login_amt >= ? AND julianday(first_seen) < julianday(?)"
         );
-        queries.extend(self.play_time.iter().map(|query| {
-            format!(
-                "play_time {} '{}'",
-                query.operator.to_string(),
-                query.val.whole_seconds()
-            )
-        }));
-        queries.extend(self.time_online.iter().map(|query| {
-            format!(
-                "time_online {} '{}'",
-                query.operator.to_string(),
-                query.val.whole_seconds()
-            )
-        }));
-        queries.extend(self.login_amt.iter().map(|query| {
-            format!(
-                "login_amt {} {}",
-                query.operator.to_string(),
-                wrap_to_i64(query.val)
-            )
-        }));
-        queries.extend(self.first_seen.iter().map(|query| {
-            format!(
-                "first_seen {} '{}'",
-                query.operator.to_string(),
-                query.val.format(&Rfc3339).expect("Format date correctly")
-            )
-        }));
-        queries.extend(self.last_seen.iter().map(|query| {
-            format!(
-                "last_seen {} '{}'",
-                query.operator.to_string(),
-                query.val.format(&Rfc3339).expect("Format date correctly")
-            )
-        }));
-        queries.join(" AND ")
+        let mut query = QueryBuilder::<Postgres>::new("SELECT * FROM lurkies");
+        Restriction::default().push_postgres(&mut query);
+        assert_eq!(query.sql(), "SELECT * FROM lurkies");
     }
 }
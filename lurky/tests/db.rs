@@ -0,0 +1,806 @@
+// the same behaviour checks, ran against every DB backend
+// postgres only runs when LURKY_TEST_POSTGRES_URL points at a database that can be wiped
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use lurky::{
+    auth::{hash_key, Scope},
+    config::{LogFormat, LurkyConfig, RateLimits},
+    db::{create_db_from_config, DBPlayer, Flag, LeaderboardEntry, LurkyError, ManagedDB},
+    query::{
+        Leaderboard, LeaderboardStat, NicknameMatch, NicknameQuery, Operator, Page, Query,
+        Restriction, Sort, SortField, SortOrder, MAX_LIMIT,
+    },
+};
+use time::OffsetDateTime;
+
+fn config(db_type: &str, db_url: &str) -> LurkyConfig {
+    LurkyConfig {
+        servers: vec![],
+        auth_key: "test".to_string(),
+        db_type: db_type.to_string(),
+        db_url: db_url.to_string(),
+        refresh_cooldown: std::time::Duration::from_secs(60),
+        nw_api_url: String::new(),
+        rate_limits: RateLimits::default(),
+        http_cat_dir: String::new(),
+        log_level: String::new(),
+        log_format: LogFormat::Text,
+        trusted_proxies: vec![],
+    }
+}
+
+async fn memory_db() -> ManagedDB {
+    let mut db = create_db_from_config(&config("memory", "")).unwrap();
+    db.setup().await.unwrap();
+    db
+}
+
+static SQLITE_DBS: AtomicUsize = AtomicUsize::new(0);
+
+async fn sqlite_db() -> ManagedDB {
+    let path = std::env::temp_dir().join(format!(
+        "lurky-test-{}-{}.db",
+        std::process::id(),
+        SQLITE_DBS.fetch_add(1, Ordering::SeqCst)
+    ));
+    let _ = std::fs::remove_file(&path);
+    let mut db =
+        create_db_from_config(&config("sqlite", &format!("sqlite://{}", path.display()))).unwrap();
+    db.setup().await.unwrap();
+    db
+}
+
+async fn postgres_db(url: &str) -> ManagedDB {
+    let mut db = create_db_from_config(&config("postgres", url)).unwrap();
+    db.setup().await.unwrap();
+    let pool = sqlx::PgPool::connect(url).await.unwrap();
+    sqlx::query("TRUNCATE lurkies, sessions, server_stats, api_keys RESTART IDENTITY CASCADE")
+        .execute(&pool)
+        .await
+        .unwrap();
+    db
+}
+
+fn at(unix: i64) -> OffsetDateTime {
+    OffsetDateTime::from_unix_timestamp(unix).unwrap()
+}
+
+fn player(id: u64, nick: &str, play_time: i64, login_amt: u64, first_seen: i64) -> DBPlayer {
+    DBPlayer {
+        id,
+        first_seen: at(first_seen),
+        last_seen: at(first_seen + play_time),
+        play_time: time::Duration::seconds(play_time),
+        last_nickname: nick.to_string(),
+        nicknames: vec![nick.to_string()],
+        flags: vec![],
+        time_online: time::Duration::seconds(play_time),
+        login_amt,
+    }
+}
+
+fn flag(flag: i64) -> Flag {
+    Flag {
+        flag,
+        issuer: "tester".to_string(),
+        issued_at: at(1_600_000_000),
+        comment: format!("flag {}", flag),
+        revoked_at: None,
+        revoked_by: None,
+    }
+}
+
+fn ids(players: &[DBPlayer]) -> Vec<u64> {
+    let mut ids: Vec<u64> = players.iter().map(|p| p.id).collect();
+    ids.sort();
+    ids
+}
+
+async fn seed(db: &ManagedDB) {
+    let mut a = player(1, "alpha", 100, 1, 1_600_000_000);
+    a.flags = vec![flag(1)];
+    let mut b = player(u64::MAX, "bravo", 5000, 50, 1_650_000_000);
+    b.flags = vec![flag(1), flag(2)];
+    let c = player(76561197960265728, "charlie", 3600, 200, 1_670_000_000);
+    for p in [a, b, c] {
+        db.create_player(p).await.unwrap();
+    }
+}
+
+async fn check_players(db: ManagedDB) {
+    seed(&db).await;
+    assert!(db.has_player(1).await.unwrap());
+    assert!(!db.has_player(2).await.unwrap());
+    assert!(matches!(
+        db.get_player(2).await,
+        Err(LurkyError::NotFound(_))
+    ));
+    let a = db.get_player(1).await.unwrap();
+    assert!(matches!(
+        db.create_player(a).await,
+        Err(LurkyError::Conflict(_))
+    ));
+
+    let mut b = db.get_player(u64::MAX).await.unwrap();
+    assert_eq!(b.last_nickname, "bravo");
+    assert_eq!(b.first_seen, at(1_650_000_000));
+    assert_eq!(b.play_time, time::Duration::seconds(5000));
+    assert_eq!(b.login_amt, 50);
+    assert_eq!(b.flags.len(), 2);
+
+    b.last_nickname = "bravo2".to_string();
+    b.nicknames.push("bravo2".to_string());
+    b.login_amt += 1;
+    db.update_player(b).await.unwrap();
+    let b = db.get_by_latest_nickname("bravo2").await.unwrap();
+    assert_eq!(b.id, u64::MAX);
+    assert_eq!(b.nicknames, vec!["bravo", "bravo2"]);
+    assert_eq!(b.login_amt, 51);
+    assert!(db.get_by_latest_nickname("bravo").await.is_err());
+}
+
+async fn check_restrictions(db: ManagedDB) {
+    seed(&db).await;
+    let all = db
+        .get_by_restriction(&Restriction::default(), &Page::default())
+        .await
+        .unwrap()
+        .players;
+    assert_eq!(all.len(), 3);
+
+    let flagged = Restriction {
+        flags: vec![1, 2],
+        ..Default::default()
+    };
+    assert_eq!(
+        ids(&db
+            .get_by_restriction(&flagged, &Page::default())
+            .await
+            .unwrap()
+            .players),
+        vec![u64::MAX]
+    );
+
+    let logins = Restriction {
+        login_amt: vec![
+            Query {
+                operator: Operator::GreaterThan,
+                val: 1,
+            },
+            Query {
+                operator: Operator::LessThanEqualTo,
+                val: 200,
+            },
+        ],
+        ..Default::default()
+    };
+    assert_eq!(
+        ids(&db
+            .get_by_restriction(&logins, &Page::default())
+            .await
+            .unwrap()
+            .players),
+        vec![76561197960265728, u64::MAX]
+    );
+
+    let play_time = Restriction {
+        play_time: vec![Query {
+            operator: Operator::GreaterThanEqualTo,
+            val: time::Duration::seconds(3600),
+        }],
+        time_online: vec![Query {
+            operator: Operator::NotEqualTo,
+            val: time::Duration::seconds(5000),
+        }],
+        ..Default::default()
+    };
+    assert_eq!(
+        ids(&db
+            .get_by_restriction(&play_time, &Page::default())
+            .await
+            .unwrap()
+            .players),
+        vec![76561197960265728]
+    );
+
+    let dates = Restriction {
+        first_seen: vec![Query {
+            operator: Operator::LessThan,
+            val: at(1_660_000_000),
+        }],
+        last_seen: vec![Query {
+            operator: Operator::GreaterThan,
+            val: at(1_600_000_000),
+        }],
+        ..Default::default()
+    };
+    assert_eq!(
+        ids(&db
+            .get_by_restriction(&dates, &Page::default())
+            .await
+            .unwrap()
+            .players),
+        vec![1, u64::MAX]
+    );
+
+    let random = db.get_by_restriction_random(&flagged).await.unwrap();
+    assert_eq!(random.id, u64::MAX);
+    let nobody = Restriction {
+        flags: vec![3],
+        ..Default::default()
+    };
+    assert!(db
+        .get_by_restriction(&nobody, &Page::default())
+        .await
+        .unwrap()
+        .players
+        .is_empty());
+    assert!(matches!(
+        db.get_by_restriction_random(&nobody).await,
+        Err(LurkyError::NotFound(what)) if what == "Player"
+    ));
+}
+
+async fn check_pages(db: ManagedDB) {
+    seed(&db).await;
+    for i in 0..150 {
+        db.create_player(player(
+            1000 + i,
+            &format!("filler{:03}", i),
+            i as i64,
+            2,
+            1_600_000_000,
+        ))
+        .await
+        .unwrap();
+    }
+    let page = |limit, offset, sort: &str| Page {
+        limit,
+        offset,
+        sort: sort.parse().unwrap(),
+    };
+    let get = |rest: Restriction, page: Page| {
+        let db = &db;
+        async move { db.get_by_restriction(&rest, &page).await.unwrap() }
+    };
+
+    let first = get(Restriction::default(), Page::default()).await;
+    assert_eq!(first.total, 153);
+    assert_eq!(first.players.len(), 20);
+    assert_eq!(first.players[0].id, 1);
+    assert_eq!(first.players[1].id, 1000);
+
+    let clamped = get(Restriction::default(), page(1000, 0, "id")).await;
+    assert_eq!(clamped.limit, MAX_LIMIT);
+    assert_eq!(clamped.players.len(), MAX_LIMIT as usize);
+    let rest = get(Restriction::default(), page(1000, 100, "id:asc")).await;
+    assert_eq!(rest.players.len(), 53);
+    assert_eq!(rest.players.last().unwrap().id, u64::MAX);
+    assert!(get(Restriction::default(), page(10, 500, "id"))
+        .await
+        .players
+        .is_empty());
+
+    let by_play_time = get(Restriction::default(), page(3, 0, "play_time:desc")).await;
+    assert_eq!(
+        by_play_time
+            .players
+            .iter()
+            .map(|p| p.id)
+            .collect::<Vec<_>>(),
+        vec![u64::MAX, 76561197960265728, 1000 + 149]
+    );
+    // 100 is tied between alpha and filler100, the id breaks the tie
+    let tied = get(Restriction::default(), page(3, 50, "play_time:desc")).await;
+    assert_eq!(
+        tied.players.iter().map(|p| p.id).collect::<Vec<_>>(),
+        vec![1000 + 101, 1, 1000 + 100]
+    );
+    let by_nick = get(Restriction::default(), page(2, 0, "last_nickname:desc")).await;
+    assert_eq!(
+        by_nick
+            .players
+            .iter()
+            .map(|p| p.last_nickname.as_str())
+            .collect::<Vec<_>>(),
+        vec!["filler149", "filler148"]
+    );
+    let by_login = get(Restriction::default(), page(1, 0, "login_amt:desc")).await;
+    assert_eq!(by_login.players[0].id, 76561197960265728);
+    let by_seen = get(Restriction::default(), page(2, 0, "first_seen:desc")).await;
+    assert_eq!(
+        by_seen.players.iter().map(|p| p.id).collect::<Vec<_>>(),
+        vec![76561197960265728, u64::MAX]
+    );
+
+    let filtered = Restriction {
+        login_amt: vec![Query {
+            operator: Operator::EqualTo,
+            val: 2,
+        }],
+        ..Default::default()
+    };
+    let filtered = get(filtered, page(5, 145, "last_seen:desc")).await;
+    assert_eq!(filtered.total, 150);
+    assert_eq!(
+        filtered.players.iter().map(|p| p.id).collect::<Vec<_>>(),
+        vec![1004, 1003, 1002, 1001, 1000]
+    );
+    assert_eq!(
+        "time_online:desc".parse::<Sort>().unwrap(),
+        Sort {
+            field: SortField::TimeOnline,
+            order: SortOrder::Desc
+        }
+    );
+    assert!("password:asc".parse::<Sort>().is_err());
+    assert!("id:sideways".parse::<Sort>().is_err());
+}
+
+async fn check_nicknames(db: ManagedDB) {
+    seed(&db).await;
+    let mut d = player(4, "The_Lurker", 10, 1, 1_600_000_000);
+    d.nicknames = vec![
+        "100%real".to_string(),
+        "Charlie".to_string(),
+        "The_Lurker".to_string(),
+    ];
+    db.create_player(d).await.unwrap();
+    db.create_player(player(5, "Ünholy_Chalupa", 10, 1, 1_600_000_000))
+        .await
+        .unwrap();
+
+    let search = |pattern: &str, mode: NicknameMatch, historical: bool| Restriction {
+        nickname: Some(NicknameQuery {
+            pattern: pattern.to_string(),
+            mode,
+            historical,
+        }),
+        ..Default::default()
+    };
+    let find = |rest: Restriction| {
+        let db = &db;
+        async move {
+            ids(&db
+                .get_by_restriction(&rest, &Page::default())
+                .await
+                .unwrap()
+                .players)
+        }
+    };
+    assert_eq!(
+        find(search("LURK", NicknameMatch::Substring, false)).await,
+        vec![4]
+    );
+    assert_eq!(
+        find(search("lurk", NicknameMatch::Prefix, false)).await,
+        Vec::<u64>::new()
+    );
+    assert_eq!(
+        find(search("the_", NicknameMatch::Prefix, false)).await,
+        vec![4]
+    );
+    // _ and % are not wildcards
+    assert_eq!(
+        find(search("the_l", NicknameMatch::Substring, false)).await,
+        vec![4]
+    );
+    assert_eq!(
+        find(search("t_e", NicknameMatch::Substring, false)).await,
+        Vec::<u64>::new()
+    );
+    assert_eq!(
+        find(search("0%r", NicknameMatch::Substring, true)).await,
+        vec![4]
+    );
+    assert_eq!(
+        find(search("0%e", NicknameMatch::Substring, true)).await,
+        Vec::<u64>::new()
+    );
+
+    assert_eq!(
+        find(search("char", NicknameMatch::Prefix, false)).await,
+        vec![76561197960265728]
+    );
+    assert_eq!(
+        find(search("char", NicknameMatch::Prefix, true)).await,
+        vec![4, 76561197960265728]
+    );
+    assert_eq!(
+        find(search("arlie", NicknameMatch::Prefix, true)).await,
+        Vec::<u64>::new()
+    );
+    assert_eq!(
+        find(search("arlie", NicknameMatch::Substring, true)).await,
+        vec![4, 76561197960265728]
+    );
+    let combined = Restriction {
+        login_amt: vec![Query {
+            operator: Operator::GreaterThan,
+            val: 1,
+        }],
+        ..search("arlie", NicknameMatch::Substring, true)
+    };
+    assert_eq!(find(combined).await, vec![76561197960265728]);
+
+    // ascii case is ignored everywhere, other letters only match in the same case
+    // on every backend (sqlite doesn't fold them)
+    assert_eq!(
+        find(search("nholy_CHALUPA", NicknameMatch::Substring, false)).await,
+        vec![5]
+    );
+    assert_eq!(
+        find(search("Ünh", NicknameMatch::Prefix, false)).await,
+        vec![5]
+    );
+}
+
+fn scores(board: &[LeaderboardEntry]) -> Vec<(u64, i64)> {
+    board.iter().map(|e| (e.player.id, e.score)).collect()
+}
+
+async fn check_leaderboard(db: ManagedDB) {
+    seed(&db).await;
+    let board = db
+        .leaderboard(&Leaderboard {
+            limit: 2,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(
+        scores(&board),
+        vec![(u64::MAX, 5000), (76561197960265728, 3600)]
+    );
+    let board = db
+        .leaderboard(&Leaderboard {
+            by: LeaderboardStat::LoginAmt,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(
+        scores(&board),
+        vec![(76561197960265728, 200), (u64::MAX, 50), (1, 1)]
+    );
+    let board = db
+        .leaderboard(&Leaderboard {
+            by: LeaderboardStat::LoginAmt,
+            restriction: Restriction {
+                flags: vec![1],
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(scores(&board), vec![(u64::MAX, 50), (1, 1)]);
+
+    db.start_session(u64::MAX, 1, at(1_699_000_000))
+        .await
+        .unwrap();
+    db.end_session(u64::MAX, at(1_699_000_100)).await.unwrap();
+    db.start_session(1, 1, at(1_700_000_000)).await.unwrap();
+    db.end_session(1, at(1_700_000_600)).await.unwrap();
+    db.start_session(1, 1, at(1_700_001_000)).await.unwrap();
+    db.end_session(1, at(1_700_001_030)).await.unwrap();
+    db.start_session(76561197960265728, 1, at(1_700_000_400))
+        .await
+        .unwrap();
+    db.end_session(76561197960265728, at(1_700_002_400))
+        .await
+        .unwrap();
+    db.start_session(76561197960265728, 2, at(1_700_003_000))
+        .await
+        .unwrap();
+    db.end_session(76561197960265728, at(1_700_003_010))
+        .await
+        .unwrap();
+
+    // sessions only count for the part after since
+    let since = Some(at(1_700_000_500));
+    let board = db
+        .leaderboard(&Leaderboard {
+            since,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(scores(&board), vec![(76561197960265728, 1910), (1, 130)]);
+    let board = db
+        .leaderboard(&Leaderboard {
+            by: LeaderboardStat::LoginAmt,
+            since,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(scores(&board), vec![(1, 1), (76561197960265728, 1)]);
+    let board = db
+        .leaderboard(&Leaderboard {
+            since,
+            limit: 1,
+            restriction: Restriction {
+                flags: vec![1],
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(scores(&board), vec![(1, 130)]);
+    assert!(matches!(
+        db.leaderboard(&Leaderboard {
+            by: LeaderboardStat::TimeOnline,
+            since,
+            ..Default::default()
+        })
+        .await,
+        Err(LurkyError::InvalidQuery(_))
+    ));
+}
+
+async fn check_server_stats(db: ManagedDB) {
+    seed(&db).await;
+    let minute = time::Duration::minutes(1);
+    assert!(db
+        .record_server_stats(2, 10, 7777, at(1_700_000_000), minute, true)
+        .await
+        .is_err());
+    db.record_server_stats(1, 10, 7777, at(1_700_000_000), minute, true)
+        .await
+        .unwrap();
+    db.record_server_stats(1, 10, 7778, at(1_700_000_060), minute, false)
+        .await
+        .unwrap();
+    db.record_server_stats(1, 11, 7779, at(1_700_000_120), minute * 5, true)
+        .await
+        .unwrap();
+    db.record_server_stats(u64::MAX, 10, 7777, at(1_700_000_000), minute * 3, true)
+        .await
+        .unwrap();
+    db.record_server_stats(1, 11, 7779, at(1_700_000_180), minute, true)
+        .await
+        .unwrap();
+
+    let stats = db.get_server_stats(1).await.unwrap();
+    assert_eq!(
+        stats.iter().map(|s| s.server_id).collect::<Vec<_>>(),
+        vec![11, 10]
+    );
+    assert_eq!(stats[1].port, 7778);
+    assert_eq!(stats[1].play_time, minute * 2);
+    assert_eq!(stats[1].login_amt, 1);
+    assert_eq!(stats[1].first_seen, at(1_700_000_000));
+    assert_eq!(stats[1].last_seen, at(1_700_000_060));
+    // counts come back as they went in, next to wrapped ids
+    assert_eq!(stats[0].login_amt, 2);
+    assert_eq!(stats[0].play_time, minute * 6);
+    let max = db.get_server_stats(u64::MAX).await.unwrap();
+    assert_eq!((max[0].server_id, max[0].login_amt), (10, 1));
+    assert!(db
+        .get_server_stats(76561197960265728)
+        .await
+        .unwrap()
+        .is_empty());
+
+    let board = db
+        .leaderboard(&Leaderboard {
+            server: Some(10),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(scores(&board), vec![(u64::MAX, 180), (1, 120)]);
+    let board = db
+        .leaderboard(&Leaderboard {
+            by: LeaderboardStat::LoginAmt,
+            server: Some(11),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(scores(&board), vec![(1, 2)]);
+
+    // with since it comes from the sessions on that server
+    db.start_session(1, 10, at(1_700_000_000)).await.unwrap();
+    db.end_session(1, at(1_700_000_100)).await.unwrap();
+    db.start_session(1, 11, at(1_700_000_100)).await.unwrap();
+    db.end_session(1, at(1_700_000_400)).await.unwrap();
+    let board = db
+        .leaderboard(&Leaderboard {
+            since: Some(at(1_699_000_000)),
+            server: Some(11),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(scores(&board), vec![(1, 300)]);
+    assert!(db
+        .leaderboard(&Leaderboard {
+            by: LeaderboardStat::TimeOnline,
+            server: Some(10),
+            ..Default::default()
+        })
+        .await
+        .is_err());
+}
+
+async fn check_flags(db: ManagedDB) {
+    seed(&db).await;
+    db.add_flag(76561197960265728, flag(5)).await.unwrap();
+    db.add_flag(76561197960265728, flag(6)).await.unwrap();
+    let flags = db.get_flags(76561197960265728).await.unwrap();
+    assert_eq!(flags.iter().map(|f| f.flag).collect::<Vec<_>>(), vec![5, 6]);
+    assert_eq!(flags[0].comment, "flag 5");
+    assert_eq!(flags[0].issued_at, at(1_600_000_000));
+
+    db.revoke_flag(76561197960265728, 5, "admin", at(1_700_000_000))
+        .await
+        .unwrap();
+    let flags = db.get_flags(76561197960265728).await.unwrap();
+    // revoked flags are kept, in the order they were issued
+    assert_eq!(flags.iter().map(|f| f.flag).collect::<Vec<_>>(), vec![5, 6]);
+    assert_eq!(flags[0].revoked_at, Some(at(1_700_000_000)));
+    assert_eq!(flags[0].revoked_by.as_deref(), Some("admin"));
+    assert_eq!(flags[0].comment, "flag 5");
+    assert!(flags[1].is_active());
+    assert!(db
+        .revoke_flag(76561197960265728, 5, "admin", at(1_700_000_001))
+        .await
+        .is_err());
+    // a revoked flag doesn't count for restrictions anymore
+    let rest = Restriction {
+        flags: vec![5],
+        ..Default::default()
+    };
+    assert!(db
+        .get_by_restriction(&rest, &Page::default())
+        .await
+        .unwrap()
+        .players
+        .is_empty());
+    let rest = Restriction {
+        flags: vec![6],
+        ..Default::default()
+    };
+    assert_eq!(
+        ids(&db
+            .get_by_restriction(&rest, &Page::default())
+            .await
+            .unwrap()
+            .players),
+        vec![76561197960265728]
+    );
+
+    // issuing it again and revoking only touches the new one
+    db.add_flag(76561197960265728, flag(5)).await.unwrap();
+    db.revoke_flag(76561197960265728, 5, "someone", at(1_700_000_100))
+        .await
+        .unwrap();
+    let flags = db.get_flags(76561197960265728).await.unwrap();
+    assert_eq!(flags.len(), 3);
+    assert_eq!(flags[0].revoked_by.as_deref(), Some("admin"));
+    assert_eq!(flags[2].revoked_by.as_deref(), Some("someone"));
+
+    assert!(db.add_flag(2, flag(1)).await.is_err());
+    assert!(db.get_flags(2).await.is_err());
+}
+
+async fn check_sessions(db: ManagedDB) {
+    seed(&db).await;
+    assert!(db.start_session(2, 1, at(1_700_000_000)).await.is_err());
+    db.start_session(1, 10, at(1_700_000_000)).await.unwrap();
+    db.end_session(1, at(1_700_000_600)).await.unwrap();
+    db.start_session(1, 11, at(1_700_001_000)).await.unwrap();
+    db.end_session(1, at(1_700_001_030)).await.unwrap();
+    db.start_session(1, 10, at(1_700_002_000)).await.unwrap();
+
+    let sessions = db.get_sessions(1, 10, 0).await.unwrap();
+    assert_eq!(sessions.len(), 3);
+    assert!(sessions[0].ended.is_none());
+    assert_eq!(sessions[1].server_id, 11);
+    assert_eq!(sessions[1].ended, Some(at(1_700_001_030)));
+    assert_eq!(sessions[1].duration, time::Duration::seconds(30));
+    assert_eq!(sessions[2].duration, time::Duration::seconds(600));
+
+    let page = db.get_sessions(1, 1, 1).await.unwrap();
+    assert_eq!(page.len(), 1);
+    assert_eq!(page[0].started, at(1_700_001_000));
+
+    // player 1 was last seen at 1_600_000_100, before the session even started
+    db.end_stale_sessions().await.unwrap();
+    let sessions = db.get_sessions(1, 10, 0).await.unwrap();
+    assert_eq!(sessions[0].ended, Some(at(1_700_002_000)));
+    assert_eq!(sessions[0].duration, time::Duration::ZERO);
+    assert!(db.get_sessions(u64::MAX, 10, 0).await.unwrap().is_empty());
+}
+
+async fn check_api_keys(db: ManagedDB) {
+    assert!(db.list_api_keys().await.unwrap().is_empty());
+    let reader = db
+        .create_api_key("reader", &[Scope::ReadPlayers], &hash_key("a"))
+        .await
+        .unwrap();
+    assert_eq!(reader.name, "reader");
+    assert!(reader.allows(Scope::ReadPlayers));
+    assert!(!reader.allows(Scope::WriteFlags));
+    let admin = db
+        .create_api_key("admin", &[Scope::Admin], &hash_key("b"))
+        .await
+        .unwrap();
+    assert!(admin.allows(Scope::WriteFlags));
+    assert!(matches!(
+        db.create_api_key("reader", &[Scope::Admin], &hash_key("c"))
+            .await,
+        Err(LurkyError::Conflict(_))
+    ));
+
+    let found = db.get_api_key(&hash_key("a")).await.unwrap();
+    assert_eq!(found.id, reader.id);
+    assert_eq!(found.scopes, vec![Scope::ReadPlayers]);
+    assert!(matches!(
+        db.get_api_key(&hash_key("c")).await,
+        Err(LurkyError::NotFound(_))
+    ));
+
+    let keys = db.list_api_keys().await.unwrap();
+    assert_eq!(
+        keys.iter().map(|k| k.name.as_str()).collect::<Vec<_>>(),
+        vec!["reader", "admin"]
+    );
+    db.delete_api_key(reader.id).await.unwrap();
+    assert!(db.delete_api_key(reader.id).await.is_err());
+    assert!(db.get_api_key(&hash_key("a")).await.is_err());
+    assert_eq!(db.list_api_keys().await.unwrap().len(), 1);
+
+    // ids aren't reused, even for the newest key
+    db.delete_api_key(admin.id).await.unwrap();
+    let again = db
+        .create_api_key("again", &[Scope::ReadPlayers], &hash_key("d"))
+        .await
+        .unwrap();
+    assert!(again.id > admin.id);
+}
+
+macro_rules! suite {
+    ($make:expr) => {
+        check_players($make).await;
+        check_restrictions($make).await;
+        check_pages($make).await;
+        check_nicknames($make).await;
+        check_leaderboard($make).await;
+        check_server_stats($make).await;
+        check_flags($make).await;
+        check_sessions($make).await;
+        check_api_keys($make).await;
+    };
+}
+
+#[tokio::test]
+async fn memory() {
+    suite!(memory_db().await);
+}
+
+#[tokio::test]
+async fn sqlite() {
+    suite!(sqlite_db().await);
+}
+
+#[tokio::test]
+async fn sqlite_in_memory() {
+    suite!({
+        let mut db = create_db_from_config(&config("sqlite", "sqlite::memory:")).unwrap();
+        db.setup().await.unwrap();
+        db
+    });
+}
+
+#[tokio::test]
+async fn postgres() {
+    let Ok(url) = std::env::var("LURKY_TEST_POSTGRES_URL") else {
+        eprintln!("LURKY_TEST_POSTGRES_URL not set, skipping");
+        return;
+    };
+    suite!(postgres_db(&url).await);
+}
@@ -19,6 +19,9 @@ use time::OffsetDateTime;
 struct Args {
     config: PathBuf,
     inputs: Vec<PathBuf>,
+    /// override a config value, these win over the file and LURKY_* environment variables
+    #[clap(short, long, value_name = "KEY=VALUE")]
+    set: Vec<String>,
 }
 
 impl Args {
@@ -34,8 +37,8 @@ impl Args {
                 return Err(format!("Input file does not exist: {}", input.display()));
             }
         }
-        if self.inputs.len() == 0 {
-            return Err(format!("No inputs provided"));
+        if self.inputs.is_empty() {
+            return Err("No inputs provided".to_string());
         }
         Ok(())
     }
@@ -95,12 +98,14 @@ struct RawFlag {
 }
 
 impl RawFlag {
-    fn to_flag(self) -> db::Flag {
+    fn into_flag(self) -> db::Flag {
         db::Flag {
             comment: self.Comment,
             flag: self.Flag as i64,
             issuer: self.Issuer,
             issued_at: self.IssueTime,
+            revoked_at: None,
+            revoked_by: None,
         }
     }
 }
@@ -112,12 +117,19 @@ async fn main() -> Result<(), anyhow::Error> {
         eprintln!("Error: {}", e);
         std::process::exit(1);
     }
-    let config = Arc::new(LurkyConfig::parse_data(std::fs::File::open(args.config)?));
-    println!("{:?}", config);
+    let config = match LurkyConfig::load(&args.config, &args.set) {
+        Ok(config) => Arc::new(config),
+        Err(report) => {
+            report.eprint();
+            std::process::exit(1);
+        }
+    };
+    lurky::logging::init(&config)?;
+    tracing::info!(?config, "Loaded the config");
     let mut db = db::create_db_from_config(&config)?;
     db.setup().await?;
     let db = Arc::new(db);
-    println!("Collecting files...");
+    tracing::info!("Collecting files...");
     let inputs: HashSet<String> = args
         .inputs
         .iter()
@@ -129,14 +141,14 @@ async fn main() -> Result<(), anyhow::Error> {
                 .filter_map(|e| {
                     e.path()
                         .file_name()
-                        .and_then(|e| Some(e.to_string_lossy().to_string()))
+                        .map(|e| e.to_string_lossy().to_string())
                 })
                 .filter(|e| e.ends_with(".json"))
         })
         .collect();
-    println!("Found {} users", inputs.len());
+    tracing::info!(users = inputs.len(), "Found the users");
     // collect files
-    println!("Collecting unique users...");
+    tracing::info!("Collecting unique users...");
     let files: Vec<Vec<PathBuf>> = inputs
         .iter()
         .map(|i| {
@@ -153,7 +165,7 @@ async fn main() -> Result<(), anyhow::Error> {
                 .collect()
         })
         .collect();
-    println!("Begining migration...");
+    tracing::info!("Begining migration...");
     let pb = indicatif::ProgressBar::new(files.len() as u64);
     pb.set_style(
         indicatif::ProgressStyle::default_bar()
@@ -174,10 +186,10 @@ async fn main() -> Result<(), anyhow::Error> {
             .iter()
             .map(|f| {
                 let file = fs::File::open(f)
-                    .expect(format!("Failed to open file {}", f.display()).as_str());
+                    .unwrap_or_else(|_| panic!("Failed to open file {}", f.display()));
                 let buferr = std::io::BufReader::new(file);
                 serde_json::from_reader(buferr)
-                    .expect(format!("Failed to read file {}", f.display()).as_str())
+                    .unwrap_or_else(|_| panic!("Failed to read file {}", f.display()))
             })
             .collect::<Vec<RawSCP>>();
         // time to merge them
@@ -193,10 +205,7 @@ async fn main() -> Result<(), anyhow::Error> {
             .expect("Failed to find max date");
         let first_seen = read_files
             .iter()
-            .map(|e| {
-                time::OffsetDateTime::parse(&e.FirstSeen, &Rfc3339)
-                    .unwrap_or_else(|_| last_seen.clone())
-            })
+            .map(|e| time::OffsetDateTime::parse(&e.FirstSeen, &Rfc3339).unwrap_or(last_seen))
             .min()
             .expect("Failed to find min date");
         // playtime is easy
@@ -233,10 +242,7 @@ async fn main() -> Result<(), anyhow::Error> {
 
         let all_nicks: HashSet<String> = read_files
             .iter()
-            .flat_map(|e| {
-                (e.Usernames.iter().map(|(_, v)| v.clone()))
-                    .chain(e.LastNickname.clone().into_iter())
-            })
+            .flat_map(|e| (e.Usernames.values().cloned()).chain(e.LastNickname.clone().into_iter()))
             .collect();
         //println!("All nicks: {:?}", all_nicks);
 
@@ -270,7 +276,7 @@ async fn main() -> Result<(), anyhow::Error> {
             play_time,
             last_nickname,
             nicknames: all_nicks.into_iter().collect(),
-            flags: flags.into_iter().map(|e| e.clone().to_flag()).collect(),
+            flags: flags.into_iter().map(|e| e.clone().into_flag()).collect(),
             time_online,
             login_amt: login_amount,
         };